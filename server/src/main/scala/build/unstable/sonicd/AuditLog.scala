@@ -0,0 +1,127 @@
+package build.unstable.sonicd
+
+import java.time.Instant
+
+import build.unstable.sonicd.system.actor.SonicdController.ActiveQuery
+import ch.qos.logback.classic.encoder.PatternLayoutEncoder
+import ch.qos.logback.classic.spi.ILoggingEvent
+import ch.qos.logback.classic.{AsyncAppender, Level, LoggerContext}
+import ch.qos.logback.core.rolling._
+import org.slf4j.LoggerFactory
+import spray.json._
+
+object AuditLog {
+
+  /**
+    * @param rotation    either 'size', to roll over when the file reaches `maxFileSize`, or 'daily'
+    * @param maxFiles    rolled over files retained
+    * @param compress    gzip rolled over files
+    */
+  case class Settings(path: String, rotation: String, maxFileSize: String,
+                      maxFiles: Int, compress: Boolean, queryMaxLength: Int) {
+    require(rotation == "size" || rotation == "daily", s"invalid audit log rotation '$rotation'. Use 'size' or 'daily'")
+  }
+
+}
+
+/**
+  * Appends a JSON line per executed query to `settings.path`, independently of the logback configuration.
+  * Query auth and source configs are never written: only the source alias or class is.
+  *
+  * Rotation is done by logback's rolling appender, which renames the active file
+  * before opening a new one so entries are never split across files.
+  *
+  * Entries are written by an [[AsyncAppender]] on its own thread, as rolling over may compress
+  * the rolled file synchronously and [[log]] is called from within [[SonicdController]].
+  * Its queue never discards entries, so [[log]] only blocks if the writer falls that far behind.
+  */
+class AuditLog(settings: AuditLog.Settings) {
+
+  private val ctx = LoggerFactory.getILoggerFactory.asInstanceOf[LoggerContext]
+
+  private val appender = new RollingFileAppender[ILoggingEvent]
+
+  private val async = new AsyncAppender
+
+  private val logger = ctx.getLogger(s"sonicd.audit.${settings.path}")
+
+  {
+    val suffix = if (settings.compress) ".gz" else ""
+
+    appender.setContext(ctx)
+    appender.setName(s"audit-${settings.path}")
+    appender.setFile(settings.path)
+
+    settings.rotation match {
+      case "size" ⇒
+        val policy = new FixedWindowRollingPolicy
+        policy.setContext(ctx)
+        policy.setParent(appender)
+        policy.setFileNamePattern(settings.path + ".%i" + suffix)
+        policy.setMinIndex(1)
+        policy.setMaxIndex(settings.maxFiles)
+        policy.start()
+
+        val trigger = new SizeBasedTriggeringPolicy[ILoggingEvent]
+        trigger.setContext(ctx)
+        trigger.setMaxFileSize(settings.maxFileSize)
+        trigger.start()
+
+        appender.setRollingPolicy(policy)
+        appender.setTriggeringPolicy(trigger)
+
+      case "daily" ⇒
+        val policy = new TimeBasedRollingPolicy[ILoggingEvent]
+        policy.setContext(ctx)
+        policy.setParent(appender)
+        policy.setFileNamePattern(settings.path + ".%d{yyyy-MM-dd}" + suffix)
+        policy.setMaxHistory(settings.maxFiles)
+        policy.start()
+
+        appender.setRollingPolicy(policy)
+    }
+
+    val encoder = new PatternLayoutEncoder
+    encoder.setContext(ctx)
+    encoder.setPattern("%msg%n")
+    encoder.start()
+
+    appender.setEncoder(encoder)
+    appender.start()
+
+    async.setContext(ctx)
+    async.setName(s"audit-async-${settings.path}")
+    async.setDiscardingThreshold(0)
+    // wait for the queue to drain on close
+    async.setMaxFlushTime(0)
+    async.addAppender(appender)
+    async.start()
+
+    logger.setAdditive(false)
+    logger.setLevel(Level.INFO)
+    logger.addAppender(async)
+  }
+
+  def log(query: ActiveQuery, finished: Long, status: String): Unit = {
+    val entry = JsObject(
+      "trace_id" → JsString(query.traceId),
+      "user" → query.user.map(JsString(_)).getOrElse(JsNull),
+      "client_address" → query.clientAddress.map(a ⇒ JsString(a.getHostAddress)).getOrElse(JsNull),
+      "source" → JsString(query.source),
+      "query" → JsString(SonicdLogging.truncate(query.query, settings.queryMaxLength)),
+      "started" → JsString(Instant.ofEpochMilli(query.started).toString),
+      "finished" → JsString(Instant.ofEpochMilli(finished).toString),
+      "status" → JsString(status)
+    )
+    logger.info(entry.compactPrint)
+  }
+
+  /**
+    * Blocks until pending entries are written, then closes the file
+    */
+  def close(): Unit = {
+    logger.detachAppender(async)
+    async.stop()
+    appender.stop()
+  }
+}
@@ -1,19 +1,21 @@
 package build.unstable.sonicd
 
-import java.net.InetSocketAddress
-
-import akka.http.scaladsl.Http
-import akka.io.Tcp
+import akka.pattern.ask
 import akka.stream.scaladsl.{Tcp ⇒ StreamTcp}
+import akka.util.Timeout
 import build.unstable.sonicd.api.AkkaApi
-import build.unstable.sonicd.system.{AkkaService, AkkaSystem}
+import build.unstable.sonicd.system.actor.SonicdController
+import build.unstable.sonicd.system.{AkkaService, AkkaSystem, Listeners}
 import com.typesafe.sslconfig.akka.AkkaSSLConfig
 import com.typesafe.sslconfig.akka.util.AkkaLoggerFactory
 import com.typesafe.sslconfig.ssl.{ConfigSSLContextBuilder, SSLConfigFactory}
 
+import scala.concurrent.Await
+import scala.concurrent.duration._
+import scala.util.control.NonFatal
+
 object Sonicd extends App with AkkaSystem with AkkaService with AkkaApi with SonicdLogging {
 
-  val http = Http()
   val sslConfigFactory = AkkaSSLConfig()
 
   val sonicOverrides = system.settings.config.getConfig("sonicd.ssl-config")
@@ -24,15 +26,27 @@ object Sonicd extends App with AkkaSystem with AkkaService with AkkaApi with Son
   val trustManagerFactory = sslConfigFactory.buildTrustManagerFactory(config)
   val sslContext = new ConfigSSLContextBuilder(new AkkaLoggerFactory(system), config, keyManagerFactory, trustManagerFactory).build()
 
-  http.bindAndHandle(handler = httpHandler, interface = SonicdConfig.INTERFACE, port = SonicdConfig.HTTP_PORT)
+  Listeners.bind(SonicdConfig, httpHandler, tcpService)
 
-  tcpIoService.tell(Tcp.Bind(tcpService,
-    new InetSocketAddress(SonicdConfig.INTERFACE, SonicdConfig.TCP_PORT), options = Nil, pullMode = true), tcpService)
-
-  log.info( "STARTING SONIC SERVICE V.{} ({} {}) on interface {}; http port: {}; tcp port: {}",
-    BuildInfo.version, BuildInfo.commit, BuildInfo.builtAt, SonicdConfig.INTERFACE,
-    SonicdConfig.HTTP_PORT, SonicdConfig.TCP_PORT)
+  log.info( "STARTING SONIC SERVICE V.{} ({} {}); http: {}:{}; tcp: {}:{}",
+    BuildInfo.version, BuildInfo.commit, BuildInfo.builtAt,
+    SonicdConfig.HTTP_INTERFACE, SonicdConfig.HTTP_PORT, SonicdConfig.TCP_INTERFACE, SonicdConfig.TCP_PORT)
 
   log.info( "ssl config: {} with default protocol: {}", config, config.protocol)
 
+  override def shutdown(): Unit = {
+    log.info("draining active queries before shutting down")
+    try {
+      val grace = SonicdConfig.SHUTDOWN_GRACE
+      val timeout = Timeout(grace + 5.seconds)
+      Await.ready(controllerService.ask(SonicdController.Shutdown(grace))(timeout), timeout.duration)
+    } catch {
+      case NonFatal(e) ⇒ log.error(e, "error when draining active queries")
+    } finally {
+      super.shutdown()
+      // after terminating, so that entries of the queries stopped with the system are written too
+      auditLog.foreach(_.close())
+    }
+  }
+
 }
@@ -2,9 +2,11 @@ package build.unstable.sonicd
 
 import java.util.concurrent.TimeUnit
 
+import akka.io.{Inet, Tcp}
 import akka.util.Timeout
-import build.unstable.sonic.JsonProtocol
+import build.unstable.sonic.JsonProtocol._
 import build.unstable.sonicd.auth.ApiKey
+import build.unstable.sonicd.system.actor.{SonicdController, SourceCircuitBreaker}
 import com.typesafe.config.{Config, ConfigFactory, ConfigRenderOptions}
 import spray.json._
 
@@ -19,9 +21,61 @@ abstract class FromResourcesConfig(config: Config) extends SonicdLogging {
   val HTTP_PORT = config.getInt("sonicd.http-port")
   val TCP_PORT = config.getInt("sonicd.tcp-port")
   val INTERFACE = config.getString("sonicd.interface")
+  val HTTP_INTERFACE = Try(config.getString("sonicd.http-interface")).getOrElse(INTERFACE)
+  val TCP_INTERFACE = Try(config.getString("sonicd.tcp-interface")).getOrElse(INTERFACE)
+  val TCP_NO_DELAY: Boolean = Try(config.getBoolean("sonicd.tcp-no-delay")).getOrElse(true)
+  // socket options in Bind are also applied to every accepted connection
+  val TCP_SOCKET_OPTIONS: List[Inet.SocketOption] = Tcp.SO.TcpNoDelay(TCP_NO_DELAY) :: Nil
 
   val API_VERSION = "v1"
 
+  val VERBOSE_ERRORS: Boolean = Try(config.getBoolean("sonicd.verbose-errors")).getOrElse(false)
+
+  val MAX_OUTPUT_ROWS: Long = Try(config.getLong("sonicd.max-output-rows")).getOrElse(0L)
+
+  val MAX_QUERY_BYTES: Long = Try(config.getLong("sonicd.max-query-bytes")).getOrElse(1048576L)
+  val REQUIRE_AUTH: Boolean = Try(config.getBoolean("sonicd.require-auth")).getOrElse(false)
+
+  val SHUTDOWN_GRACE: FiniteDuration = Try(FiniteDuration(config.getDuration("sonicd.shutdown-grace").toMillis,
+    TimeUnit.MILLISECONDS)).getOrElse(10.seconds)
+
+  val CIRCUIT_BREAKER: SourceCircuitBreaker.Settings = SourceCircuitBreaker.Settings(
+    Try(config.getInt("sonicd.circuit-breaker.max-failures")).getOrElse(0),
+    Try(FiniteDuration(config.getDuration("sonicd.circuit-breaker.window").toMillis,
+      TimeUnit.MILLISECONDS)).getOrElse(1.minute),
+    Try(FiniteDuration(config.getDuration("sonicd.circuit-breaker.cooldown").toMillis,
+      TimeUnit.MILLISECONDS)).getOrElse(30.seconds))
+
+  // malformed entries fail on startup rather than silently leaving queries without a timeout
+  val QUERY_TIMEOUTS: Map[String, SonicdController.QueryTimeouts] =
+    if (!config.hasPath("sonicd.timeouts")) Map.empty
+    else {
+      val timeouts = config.getConfig("sonicd.timeouts")
+      timeouts.root().keySet().map { sourceClass ⇒
+        val c = timeouts.getConfig(sourceClass)
+        def duration(key: String): Option[FiniteDuration] = if (!c.hasPath(key)) None else {
+          val d = FiniteDuration(c.getDuration(key).toMillis, TimeUnit.MILLISECONDS)
+          require(d > Duration.Zero, s"'sonicd.timeouts.$sourceClass.$key' must be greater than 0")
+          Some(d)
+        }
+        val t = SonicdController.QueryTimeouts(duration("default"), duration("max"))
+        require(t.default.forall(d ⇒ t.max.forall(d <= _)),
+          s"'sonicd.timeouts.$sourceClass.default' must not be greater than its 'max'")
+        sourceClass → t
+      }.toMap
+    }
+
+  val AUDIT_LOG: Option[AuditLog.Settings] = Try(config.getString("sonicd.audit-log.path")).toOption.map { path ⇒
+    AuditLog.Settings(path,
+      Try(config.getString("sonicd.audit-log.rotation")).getOrElse("size"),
+      Try(config.getString("sonicd.audit-log.max-file-size")).getOrElse("100MB"),
+      Try(config.getInt("sonicd.audit-log.max-files")).getOrElse(10),
+      Try(config.getBoolean("sonicd.audit-log.compress")).getOrElse(true),
+      Try(config.getInt("sonicd.audit-log.query-max-length")).getOrElse(4096))
+  }
+
+  val LOG_QUERY_MAX_LENGTH: Int = Try(config.getInt("sonicd.log-query-max-length")).getOrElse(200)
+
   val JDBC_FETCHSIZE = Try(config.getInt("sonicd.jdbc.fetch-size")).getOrElse(1000)
 
   val AUTH_WORKERS: Int = config.getInt("sonicd.auth-workers")
@@ -42,6 +96,14 @@ abstract class FromResourcesConfig(config: Config) extends SonicdLogging {
 
   assert(API_KEYS.distinct.size == API_KEYS.size)
 
+  val ACL: Map[String, Set[String]] =
+    if (!config.hasPath("sonicd.acl")) Map.empty
+    else Try(config.getObject("sonicd.acl")
+      .render(ConfigRenderOptions.concise())
+      .parseJson.convertTo[Map[String, Set[String]]]).recover {
+      case e: Exception ⇒ throw new Exception("'sonicd.acl' must be an object of user names to lists of source aliases", e)
+    }.get
+
   val ZUORA_MAX_FETCH_SIZE = Try(config.getInt("sonicd.zuora.query_limit")).getOrElse(2000)
   //https://knowledgecenter.zuora.com/DC_Developers/SOAP_API/E_SOAP_API_Calls/query_call
   assert(ZUORA_MAX_FETCH_SIZE <= 2000)
@@ -80,5 +142,8 @@ abstract class FromResourcesConfig(config: Config) extends SonicdLogging {
 
   val KAFKA_MAX_PARTITIONS = config.getInt("sonicd.kafka.max-partitions")
   val KAFKA_BROADCAST_BUFFER_SIZE = config.getInt("sonicd.kafka.broadcast-buffer-size")
+
+  val CONTROLLER: SonicdController.Settings = SonicdController.Settings(ACTOR_TIMEOUT, ACL, VERBOSE_ERRORS,
+    MAX_OUTPUT_ROWS, CIRCUIT_BREAKER, QUERY_TIMEOUTS, MAX_QUERY_BYTES, REQUIRE_AUTH)
 }
 
@@ -3,6 +3,19 @@ package build.unstable.sonicd
 import build.unstable.tylog.TypedLogging
 import org.slf4j.{Logger, LoggerFactory}
 
+object SonicdLogging {
+
+  /**
+    * Truncates `s` to at most `max` code points, appending an ellipsis if anything
+    * was cut. Never splits a surrogate pair, so it is safe to use on arbitrary query text.
+    */
+  def truncate(s: String, max: Int): String = {
+    val limit = math.max(max, 0)
+    if (s.codePointCount(0, s.length) <= limit) s
+    else s.substring(0, s.offsetByCodePoints(0, limit)) + "…"
+  }
+}
+
 trait SonicdLogging extends TypedLogging {
 
   type TraceID = String
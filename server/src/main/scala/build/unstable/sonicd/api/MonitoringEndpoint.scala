@@ -1,23 +1,38 @@
 package build.unstable.sonicd.api
 
 import akka.actor.{ActorRef, ActorSystem}
+import akka.http.scaladsl.model.{ContentTypes, HttpEntity}
 import akka.http.scaladsl.server.Directives._
 import akka.http.scaladsl.server.Route
+import akka.pattern.ask
 import akka.stream.ActorMaterializer
 import akka.util.Timeout
 import build.unstable.sonic.server.http.EndpointUtils
 import build.unstable.sonicd.BuildInfo
+import build.unstable.sonicd.system.actor.SonicdController
+import spray.json._
+import spray.json.DefaultJsonProtocol._
 
 class MonitoringEndpoint(responseTimeout: Timeout, controller: ActorRef)
                         (implicit val mat: ActorMaterializer, system: ActorSystem) extends EndpointUtils {
 
   implicit val t: Timeout = responseTimeout
 
+  import system.dispatcher
+
   val route: Route = path("version") {
     get {
       complete {
         s"${BuildInfo.version} (${BuildInfo.commit} ${BuildInfo.builtAt})"
       }
     }
+  } ~ path("circuit-breakers") {
+    get {
+      complete {
+        controller.ask(SonicdController.GetCircuitBreakers).mapTo[Map[String, String]].map { breakers ⇒
+          HttpEntity(ContentTypes.`application/json`, breakers.toJson.compactPrint)
+        }
+      }
+    }
   }
 }
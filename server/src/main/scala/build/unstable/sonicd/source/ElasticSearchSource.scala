@@ -145,7 +145,7 @@ class ElasticSearchPublisher(traceId: String,
 
   def nextRequest: HttpRequestCommand = {
     val payload: String = ElasticSearch.ESQueryJsonFormat.write(query, nextFrom, nextSize).compactPrint
-    log.trace("sending query: {}", payload)
+    log.trace("sending query: {}", SonicdLogging.truncate(payload, SonicdConfig.LOG_QUERY_MAX_LENGTH))
     val entity: RequestEntity =
       HttpEntity.Strict.apply(ContentTypes.`application/json`, ByteString(payload, ByteString.UTF_8))
     val httpRequest = HttpRequest.apply(HttpMethods.POST, uri, entity = entity)
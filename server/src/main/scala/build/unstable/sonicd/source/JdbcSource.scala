@@ -56,6 +56,7 @@ private [sonicd] object JdbcPublisher {
   case object Obj extends Types
   case object Arr extends Types
   case object Dec extends Types
+  case object BigDec extends Types
   case object Num extends Types
   case object Else extends Types
 }
@@ -273,6 +274,8 @@ class JdbcExecutor(query: String,
               extractValue(rs.getBoolean(pos))(JsBoolean.apply)
             case JdbcPublisher.Num ⇒ extractValue(rs.getLong(pos))(JsNumber.apply)
             case JdbcPublisher.Dec ⇒ extractValue(rs.getDouble(pos))(JsNumber.apply)
+            // never through double, to keep the precision of DECIMAL/NUMERIC columns
+            case JdbcPublisher.BigDec ⇒ extractValue(rs.getBigDecimal(pos))(d ⇒ JsNumber(BigDecimal(d)))
             case JdbcPublisher.Arr ⇒
               extractValue(rs.getArray(pos)) { value ⇒
                 JsArray(value
@@ -331,8 +334,10 @@ class JdbcExecutor(query: String,
               case "java.lang.Boolean" ⇒ JsBoolean(true) → JdbcPublisher.Bool
               case "java.lang.Object" ⇒ JsObject.empty → JdbcPublisher.Obj
               case "java.sql.Array" ⇒ JsArray.empty → JdbcPublisher.Arr
-              case "java.lang.Double" | "java.lang.Float" | "java.math.BigDecimal" ⇒
+              case "java.lang.Double" | "java.lang.Float" ⇒
                 JsNumber(0.0) → JdbcPublisher.Dec
+              case "java.math.BigDecimal" ⇒
+                JsNumber(0.0) → JdbcPublisher.BigDec
               case num if Try(classLoader.loadClass(num).getSuperclass.equals(classOf[Number])).getOrElse(false) ⇒
                 JsNumber(0) → JdbcPublisher.Num
               case e ⇒
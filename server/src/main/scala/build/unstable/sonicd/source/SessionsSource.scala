@@ -0,0 +1,84 @@
+package build.unstable.sonicd.source
+
+import akka.actor.{ActorContext, ActorRef, Props}
+import akka.stream.actor.ActorPublisher
+import akka.stream.actor.ActorPublisherMessage.{Cancel, Request}
+import build.unstable.sonic.model._
+import build.unstable.sonicd.SonicdLogging
+import build.unstable.sonicd.system.actor.SonicdController
+import spray.json._
+
+import scala.collection.mutable
+
+object SessionsSource {
+  /**
+    * Reserved source class of the meta-query answered by [[SessionsSource]]
+    */
+  val ReservedClass = "__sessions__"
+
+  val columns: Vector[(String, JsValue)] = Vector(
+    "connection" → JsString(""),
+    "client_address" → JsString(""),
+    "trace_id" → JsString(""),
+    "source" → JsString(""),
+    "elapsed_ms" → JsNumber(0),
+    "rows" → JsNumber(0)
+  )
+}
+
+/**
+  * Meta-query that lists the queries running on this node. Only admins can run it,
+  * see [[SonicdController.isAdmin]]. Query with `{"class": "__sessions__"}`
+  */
+class SessionsSource(query: Query, actorContext: ActorContext, context: RequestContext)
+  extends SonicdSource(query, actorContext, context) {
+
+  // sources are instantiated by the controller, so its context is the controller's
+  override def publisher: Props = Props(classOf[SessionsPublisher], actorContext.self, context)
+}
+
+class SessionsPublisher(controller: ActorRef)(implicit ctx: RequestContext)
+  extends ActorPublisher[SonicMessage] with SonicdLogging {
+
+  val buffer: mutable.Queue[SonicMessage] = mutable.Queue.empty
+
+  def tryPushDownstream(): Unit = {
+    while (isActive && totalDemand > 0 && buffer.nonEmpty) {
+      onNext(buffer.dequeue())
+    }
+    if (buffer.isEmpty) onCompleteThenStop()
+  }
+
+  def sessionToChunk(s: SonicdController.Session): OutputChunk =
+    OutputChunk(JsArray(
+      JsString(s.connection),
+      s.clientAddress.map(a ⇒ JsString(a.getHostAddress)).getOrElse(JsNull),
+      JsString(s.traceId),
+      JsString(s.source),
+      JsNumber(s.elapsed),
+      s.rows.map(JsNumber(_)).getOrElse(JsNull)
+    ))
+
+  def streaming: Receive = {
+    case Request(_) ⇒ tryPushDownstream()
+    case Cancel ⇒ onCompleteThenStop()
+  }
+
+  def waiting: Receive = {
+    case Request(_) ⇒ //waiting for sessions
+    case Cancel ⇒ onCompleteThenStop()
+    case SonicdController.Sessions(sessions) ⇒
+      buffer.enqueue(StreamStarted(ctx.traceId), TypeMetadata(SessionsSource.columns))
+      sessions.foreach(s ⇒ buffer.enqueue(sessionToChunk(s)))
+      buffer.enqueue(StreamCompleted.success)
+      context.become(streaming)
+      tryPushDownstream()
+  }
+
+  override def receive: Receive = {
+    case Request(_) ⇒
+      controller ! SonicdController.ListSessions
+      context.become(waiting)
+    case Cancel ⇒ onCompleteThenStop()
+  }
+}
@@ -16,7 +16,7 @@ import build.unstable.sonic.JsonProtocol._
 import build.unstable.sonic.{model, _}
 import build.unstable.sonic.model._
 import build.unstable.sonicd.source.ZuoraService._
-import build.unstable.sonicd.{Sonicd, SonicdConfig}
+import build.unstable.sonicd.{Sonicd, SonicdConfig, SonicdLogging}
 import spray.json._
 
 import scala.collection.mutable.ListBuffer
@@ -327,7 +327,7 @@ class ZuoraService(implicit connectionPool: ConnectionPool, materializer: ActorM
   }
 
   def runQuery(queryId: String, zoql: String, batchSize: Int, auth: ZuoraAuth)(sessionHeader: Session): Future[QueryResult] = {
-    log.debug("running query '{}': {}", queryId, zoql)
+    log.debug("running query '{}': {}", queryId, SonicdLogging.truncate(zoql, SonicdConfig.LOG_QUERY_MAX_LENGTH))
 
     val q = FirstQuery(zoql, queryId)
     val xml = q.xml(batchSize, sessionHeader.id)
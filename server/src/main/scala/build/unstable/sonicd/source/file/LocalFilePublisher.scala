@@ -10,7 +10,7 @@ import akka.stream.actor.ActorPublisher
 import akka.stream.actor.ActorPublisherMessage.{Cancel, Request}
 import akka.util.ByteString
 import build.unstable.sonic.model._
-import build.unstable.sonicd.SonicdLogging
+import build.unstable.sonicd.{SonicdConfig, SonicdLogging}
 import build.unstable.sonicd.source.SonicdPublisher
 import build.unstable.sonicd.source.SonicdPublisher.ParsedQuery
 import build.unstable.sonicd.source.file.FileWatcher.{Glob, PathWatchEvent}
@@ -192,7 +192,7 @@ trait LocalFilePublisher extends SonicdPublisher {
 
   final def receive: Receive = common orElse {
     case req: Request ⇒
-      log.debug("running file query {}", rawQuery)
+      log.debug("running file query {}", SonicdLogging.truncate(rawQuery, SonicdConfig.LOG_QUERY_MAX_LENGTH))
 
       try {
         val parsed = parseQuery(rawQuery)
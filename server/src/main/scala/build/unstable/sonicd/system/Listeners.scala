@@ -0,0 +1,28 @@
+package build.unstable.sonicd.system
+
+import java.net.InetSocketAddress
+
+import akka.actor.{ActorRef, ActorSystem}
+import akka.http.scaladsl.Http
+import akka.http.scaladsl.model.{HttpRequest, HttpResponse}
+import akka.io.{IO, Tcp}
+import akka.stream.Materializer
+import akka.stream.scaladsl.Flow
+import build.unstable.sonicd.FromResourcesConfig
+
+import scala.concurrent.Future
+
+object Listeners {
+
+  /**
+    * Binds the http listener on its interface and port, and the tcp one on its own in pull mode.
+    * The outcome of the tcp bind, [[Tcp.Bound]] or [[Tcp.CommandFailed]], is sent to `tcpService`,
+    * which is also the handler of its accepted connections.
+    */
+  def bind(config: FromResourcesConfig, httpHandler: Flow[HttpRequest, HttpResponse, Any], tcpService: ActorRef)
+          (implicit system: ActorSystem, materializer: Materializer): Future[Http.ServerBinding] = {
+    IO(Tcp).tell(Tcp.Bind(tcpService, new InetSocketAddress(config.TCP_INTERFACE, config.TCP_PORT),
+      options = config.TCP_SOCKET_OPTIONS, pullMode = true), tcpService)
+    Http().bindAndHandle(handler = httpHandler, interface = config.HTTP_INTERFACE, port = config.HTTP_PORT)
+  }
+}
@@ -5,7 +5,7 @@ import akka.io.{IO, Tcp}
 import akka.routing.RoundRobinPool
 import build.unstable.sonic.model.DataSource
 import build.unstable.sonic.server.system.{TcpHandler, TcpSupervisor, WsHandler}
-import build.unstable.sonicd.SonicdConfig
+import build.unstable.sonicd.{AuditLog, SonicdConfig}
 import build.unstable.sonicd.system.actor.{AuthenticationActor, SonicdController}
 
 /**
@@ -48,8 +48,10 @@ trait AkkaService extends Service {
 
   val tcpIoService: ActorRef = IO(Tcp)
 
+  val auditLog: Option[AuditLog] = SonicdConfig.AUDIT_LOG.map(new AuditLog(_))
+
   val controllerService: ActorRef = system.actorOf(Props(classOf[SonicdController],
-    authenticationService, SonicdConfig.ACTOR_TIMEOUT), "controller")
+    authenticationService, auditLog, SonicdConfig.CONTROLLER), "controller")
 
   val tcpService = system.actorOf(Props(classOf[TcpSupervisor], controllerService), "tcpSupervisor")
 
@@ -1,7 +1,14 @@
 package build.unstable.sonicd.system
 
+import java.util.concurrent.atomic.AtomicBoolean
+
 import akka.actor.ActorSystem
 import akka.stream.{ActorMaterializer, ActorMaterializerSettings}
+import sun.misc.{Signal, SignalHandler}
+
+import scala.concurrent.Await
+import scala.concurrent.duration._
+import scala.util.Try
 
 /**
  * Interface containing the [[akka.actor.ActorSystem]]
@@ -16,6 +23,7 @@ trait System {
 /**
  * It implements ``System`` by instantiating the ActorSystem and registering
  * the JVM termination hook to shutdown the ActorSystem on JVM exit.
+ * Override ``shutdown`` to run cleanup before the ActorSystem terminates.
  */
 trait AkkaSystem extends System {
   implicit val system = ActorSystem("sonicd")
@@ -24,6 +32,33 @@ trait AkkaSystem extends System {
 
   implicit val materializer: ActorMaterializer = ActorMaterializer(matSettings)
 
-  sys.addShutdownHook(system.terminate())
+  def shutdown(): Unit = Await.ready(system.terminate(), 30.seconds)
+
+  // the JVM runs shutdown hooks only once, so repeated signals do not trigger it again
+  sys.addShutdownHook(shutdown())
+
+  // not available on every platform or when the JVM runs with -Xrs, in which case the default handlers stay
+  Try {
+    val handler = new ShutdownSignalHandler(code ⇒ sys.exit(code), code ⇒ Runtime.getRuntime.halt(code))
+    Signal.handle(new Signal("INT"), handler)
+    Signal.handle(new Signal("TERM"), handler)
+  }
+
+}
+
+/**
+ * The first INT or TERM exits the JVM, which runs the shutdown hook that drains active queries.
+ * A second one while still shutting down halts the JVM immediately, without waiting for the grace period.
+ * Exit codes follow the shell convention of 128 + the signal number.
+ */
+class ShutdownSignalHandler(exit: Int ⇒ Unit, halt: Int ⇒ Unit) extends SignalHandler {
+
+  private val signaled = new AtomicBoolean(false)
 
+  // each signal is handled on its own thread, so a second one is handled while exit blocks on the hook
+  override def handle(signal: Signal): Unit = {
+    val code = 128 + signal.getNumber
+    if (signaled.compareAndSet(false, true)) exit(code)
+    else halt(code)
+  }
 }
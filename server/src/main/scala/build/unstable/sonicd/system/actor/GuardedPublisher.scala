@@ -0,0 +1,217 @@
+package build.unstable.sonicd.system.actor
+
+import akka.actor._
+import akka.stream.actor.ActorPublisher
+import akka.stream.actor.ActorPublisherMessage.{Cancel, Request, SubscriptionTimeoutExceeded}
+import akka.stream.scaladsl.{Keep, Sink, Source}
+import akka.stream.{ActorMaterializer, KillSwitches, UniqueKillSwitch}
+import build.unstable.sonic.model._
+import build.unstable.sonicd.SonicdLogging
+
+import scala.collection.mutable
+import scala.concurrent.duration._
+
+object GuardedPublisher {
+
+  val upstreamName = "upstream"
+
+  case object Ack
+
+  case object Started
+
+  case object Completed
+
+  /**
+    * Replies with the number of output rows emitted so far
+    */
+  case object GetRows
+
+  def truncatedProgress(maxOutputRows: Long): QueryProgress =
+    QueryProgress(QueryProgress.Finished, 0, None, Some(s"rows (truncated at $maxOutputRows)"))
+
+  case object QueryTimedOut
+
+  class QueryTimeoutException(timeout: FiniteDuration)
+    extends Exception(s"query timed out after ${timeout.toMillis}ms")
+
+}
+
+/**
+ * Wraps the publisher of a source and enforces server-side guardrails on its stream.
+ *
+ * If `maxOutputRows` is positive and the source emits more [[OutputChunk]]s than that, the source
+ * is canceled and the stream completes successfully after a [[QueryProgress]] signaling the truncation.
+ *
+ * If `timeout` is set, the source is canceled and the stream completes with an error if
+ * it's still running once it elapses.
+ *
+ * Registers itself with `controller` so that it can be drained on shutdown: on
+ * [[SonicdController.ShuttingDown]] the source is canceled and, after flushing buffered
+ * messages, the stream completes with a 'server shutting down' error.
+ * The outcome of the upstream is reported to `controller` to track the health of the source.
+ * If `verboseErrors` is enabled, stream errors carry their full cause chain, see [[SonicdController.VerboseException]].
+ * Stopping cancels the source, which happens when the client cancels or disconnects.
+ */
+class GuardedPublisher(upstreamProps: Props, query: SonicdController.ActiveQuery,
+                       maxOutputRows: Long, timeout: Option[FiniteDuration], verboseErrors: Boolean,
+                       controller: ActorRef)
+                      (implicit ctx: RequestContext)
+  extends ActorPublisher[SonicMessage] with SonicdLogging {
+
+  import GuardedPublisher._
+
+  @throws[Exception](classOf[Exception])
+  override def preStart(): Unit = {
+    controller ! SonicdController.QueryStarted(query)
+    timer = timeout.map(t ⇒ context.system.scheduler.scheduleOnce(t, self, QueryTimedOut)(context.dispatcher))
+  }
+
+  //in case this publisher never gets subscribed to
+  override def subscriptionTimeout: Duration = 1.minute
+
+  @throws[Exception](classOf[Exception])
+  override def postStop(): Unit = {
+    log.debug("stopping guarded publisher of '{}'", ctx.traceId)
+    if (killSwitch != null) killSwitch.shutdown()
+    timer.foreach(_.cancel())
+  }
+
+  override def unhandled(message: Any): Unit = {
+    log.warning("recv unhandled message {}", message)
+  }
+
+  implicit val materializer: ActorMaterializer = ActorMaterializer()(context)
+
+
+  /* HELPERS */
+
+  def tryPushDownstream() {
+    while (isActive && totalDemand > 0 && buffer.nonEmpty) {
+      onNext(buffer.dequeue())
+    }
+  }
+
+  def sendAckMaybe(upstream: ActorRef) {
+    if (totalDemand > 0 && buffer.isEmpty) {
+      upstream ! Ack
+      pendingAck = false
+    } else {
+      pendingAck = true
+    }
+  }
+
+
+  def verbose(done: StreamCompleted): StreamCompleted =
+    if (verboseErrors) done.copy(error = done.error.map(new SonicdController.VerboseException(_))) else done
+
+  def finish(done: StreamCompleted): Unit = {
+    controller ! SonicdController.QueryFinished(done.success)
+    context.become(commonReceive orElse terminating(verbose(done)))
+  }
+
+
+  /* STATE */
+
+  val buffer: mutable.Queue[SonicMessage] = mutable.Queue.empty
+  var killSwitch: UniqueKillSwitch = _
+  var pendingAck: Boolean = false
+  var rows: Long = 0L
+  var timer: Option[Cancellable] = None
+
+
+  /* BEHAVIOUR */
+
+  def commonReceive: Receive = {
+    case Cancel ⇒
+      log.debug("client canceled")
+      context.stop(self)
+
+    case GetRows ⇒ sender() ! rows
+
+    case SonicdController.ClientDisconnected ⇒
+      log.info("client of query '{}' disconnected; canceling source", ctx.traceId)
+      context.stop(self)
+  }
+
+  def interruptReceive: Receive = {
+    case SonicdController.ShuttingDown ⇒
+      log.info("draining query '{}' on shutdown", ctx.traceId)
+      if (killSwitch != null) killSwitch.shutdown()
+      val done = StreamCompleted.error(ctx.traceId, new SonicdController.ServerShuttingDownException)
+      context.become(commonReceive orElse terminating(verbose(done)))
+
+    case QueryTimedOut ⇒
+      log.info("query '{}' timed out after {}", ctx.traceId, timeout.get)
+      if (killSwitch != null) killSwitch.shutdown()
+      finish(StreamCompleted.error(ctx.traceId, new QueryTimeoutException(timeout.get)))
+  }
+
+  def terminating(done: StreamCompleted): Receive = {
+    tryPushDownstream()
+    if (buffer.isEmpty && isActive && totalDemand > 0) {
+      onNext(done)
+      onCompleteThenStop()
+    }
+
+    {
+      case r: Request ⇒ terminating(done)
+      case Started | Completed | Status.Failure(_) | _: SonicMessage ⇒ //upstream is done or was shut down
+      case SonicdController.ShuttingDown | QueryTimedOut ⇒ //already terminating
+    }
+  }
+
+  def materialized(upstream: ActorRef): Receive = commonReceive orElse interruptReceive orElse {
+    case Request(n) ⇒
+      tryPushDownstream()
+      if (pendingAck) sendAckMaybe(upstream)
+
+    case c: StreamCompleted ⇒ finish(c)
+
+    // only a row past the limit truncates the output, so that a query returning exactly
+    // `maxOutputRows` rows completes normally. That row is dropped
+    case _: OutputChunk if maxOutputRows > 0 && rows >= maxOutputRows ⇒
+      log.info("query '{}' exceeded the limit of {} output rows; truncating", ctx.traceId, maxOutputRows)
+      killSwitch.shutdown()
+      buffer.enqueue(truncatedProgress(maxOutputRows))
+      finish(StreamCompleted.success)
+
+    case o: OutputChunk ⇒
+      rows += 1
+      buffer.enqueue(o)
+      tryPushDownstream()
+      sendAckMaybe(upstream)
+
+    case m: SonicMessage ⇒
+      buffer.enqueue(m)
+      tryPushDownstream()
+      sendAckMaybe(upstream)
+
+    case Completed ⇒ finish(StreamCompleted.success)
+
+    case Status.Failure(e) ⇒ finish(StreamCompleted.error(ctx.traceId, e))
+  }
+
+  def waiting: Receive = commonReceive orElse interruptReceive orElse {
+    case Request(n) ⇒ //upstream not materialized yet
+    case Started ⇒
+      log.debug("materialized upstream of '{}'", ctx.traceId)
+      sender() ! Ack
+      context.become(materialized(sender()))
+    case Status.Failure(e) ⇒ finish(StreamCompleted.error(ctx.traceId, e))
+  }
+
+  override def receive: Receive = commonReceive orElse interruptReceive orElse {
+    case SubscriptionTimeoutExceeded ⇒
+      log.info("no subscriber in within subs timeout {}", subscriptionTimeout)
+      onCompleteThenStop()
+
+    //first time client requests
+    case Request(n) ⇒
+      val ref = context.actorOf(upstreamProps, upstreamName)
+      killSwitch = Source.fromPublisher[SonicMessage](ActorPublisher(ref))
+        .viaMat(KillSwitches.single)(Keep.right)
+        .to(Sink.actorRefWithAck(self, Started, Ack, Completed))
+        .run()
+      context.become(waiting)
+  }
+}
@@ -1,6 +1,7 @@
 package build.unstable.sonicd.system.actor
 
 import java.net.InetAddress
+import java.nio.charset.StandardCharsets
 
 import akka.actor.SupervisorStrategy.Restart
 import akka.actor._
@@ -8,18 +9,22 @@ import akka.pattern._
 import akka.util.Timeout
 import build.unstable.sonic.JsonProtocol._
 import build.unstable.sonic.model._
-import build.unstable.sonicd.SonicdLogging
+import build.unstable.sonicd.source.SessionsSource
+import build.unstable.sonicd.{AuditLog, SonicdConfig, SonicdLogging}
 import build.unstable.tylog.Variation
 import com.typesafe.config.{ConfigFactory, ConfigRenderOptions}
 import org.slf4j.MDC
 import org.slf4j.event.Level
 import spray.json._
 
+import scala.collection.mutable
 import scala.concurrent.Future
+import scala.concurrent.duration._
 import scala.util.control.NonFatal
 import scala.util.{Failure, Success, Try}
 
-class SonicdController(authService: ActorRef, authenticationTimeout: Timeout) extends Actor with SonicdLogging {
+class SonicdController(authService: ActorRef, auditLog: Option[AuditLog], settings: SonicdController.Settings)
+  extends Actor with SonicdLogging {
 
   import SonicdController._
 
@@ -42,6 +47,9 @@ class SonicdController(authService: ActorRef, authenticationTimeout: Timeout) ex
 
   /* HELPERS */
 
+  def failed(e: Throwable): Failure[Nothing] =
+    Failure(if (settings.verboseErrors) new VerboseException(e) else e)
+
   def prepareMaterialization(handler: ActorRef, q: Query,
                              user: Option[ApiUser], clientAddress: Option[InetAddress]): Unit = {
     try {
@@ -49,16 +57,106 @@ class SonicdController(authService: ActorRef, authenticationTimeout: Timeout) ex
       val queryId = handled
       val query = q.copy(query_id = Some(queryId))
       val source = getDataSource(query, context, user, clientAddress)
+      val sourceName = query.sourceAlias.getOrElse(query.sonicdSourceClass)
+      val circuit = circuitOf(query)
 
       log.debug("successfully instantiated source {} for query with id '{}'", source, queryId)
 
-      if (isAuthorized(user, query.sourceSecurity, clientAddress)) {
-        handler ! source.publisher
-      } else handler ! Failure(new UnauthorizedException(user, clientAddress))
+      if (shuttingDown) {
+        handler ! failed(new ServerShuttingDownException)
+      } else if (!isAuthorized(user, query.sourceSecurity, clientAddress)) {
+        handler ! failed(new UnauthorizedException(user, clientAddress))
+      } else if (!isAllowedSource(settings.acl, user, query.sourceAlias) ||
+        (source.isInstanceOf[SessionsSource] && !isAdmin(settings.acl, user))) {
+        handler ! failed(new SourceNotAllowedException(sourceName))
+      } else {
+        val sourceClass = source.getClass.getSimpleName
+        val requested = query.sonicdConfig.fields.get("timeout-ms").map(_.convertTo[Long].millis)
+        val timeout = effectiveTimeout(sourceClass, settings.timeouts.get(sourceClass), requested)
+        val publisher = source.publisher
+        // last, as it uses up the trial of a half-open circuit
+        if (!allowedByBreaker(circuit)) {
+          log.warning("rejecting query '{}': circuit of source {} is open", query.traceId.get, circuit)
+          handler ! failed(new SourceUnavailableException(circuit))
+        } else {
+          val ctx = RequestContext(query.traceId.get, user, clientAddress)
+          val running = ActiveQuery(ctx.traceId, sourceName, circuit,
+            user.map(_.user), clientAddress, query.query, handler)
+          // cancel the query if the client disconnects
+          context.watch(handler)
+          handler ! Props(classOf[GuardedPublisher], publisher, running, settings.maxOutputRows, timeout,
+            settings.verboseErrors, self, ctx)
+        }
+      }
     } catch {
       case e: Exception ⇒
         log.error(e, "error when preparing stream materialization")
-        handler ! Failure(e)
+        handler ! failed(e)
+    }
+  }
+
+
+  def authenticate(handler: ActorRef, query: Query, clientAddress: Option[InetAddress]): Unit = {
+    log.debug("client from {} posted new query with trace id '{}': {}", clientAddress,
+      query.traceId.get, SonicdLogging.truncate(query.query, SonicdConfig.LOG_QUERY_MAX_LENGTH))
+
+    log.tylog(Level.INFO, query.traceId.get, AuthenticateUser,
+      Variation.Attempt, "authenticating with auth: {}", query.auth)
+
+    query.auth match {
+      case Some(SonicdAuth(token)) ⇒
+        authService.ask(
+          ValidateToken(token, query.traceId.get))(settings.authenticationTimeout)
+          .mapTo[Try[ApiUser]]
+          .map(tu ⇒ TokenValidationResult(tu, query, handler, clientAddress))
+          .recover {
+            case e: Exception ⇒ TokenValidationResult(Failure(e), query, handler, clientAddress)
+          }.pipeTo(self)
+
+      // if no provider is passed, then use sonicds auth
+      case Some(auth) ⇒
+        val providerClass = auth.provider
+        val result = try {
+          val provider = providerClass.getConstructors()(0).newInstance().asInstanceOf[ExternalAuthProvider]
+          provider.validate(auth, context.system, query.traceId.get)
+            .map(tu ⇒ TokenValidationResult(Success(tu), query, handler, clientAddress))
+        } catch {
+          case e: Exception ⇒ Future.failed(e)
+        }
+
+        result.recover {
+          case e: Exception ⇒ TokenValidationResult(Failure(e), query, handler, clientAddress)
+        }.pipeTo(self)
+
+      case None if settings.requireAuth ⇒
+        val e = new AuthenticationRequiredException
+        log.tylog(Level.INFO, query.traceId.get, AuthenticateUser, Variation.Failure(e), "user presented no auth token")
+        handler ! failed(e)
+
+      case None ⇒
+        log.tylog(Level.INFO, query.traceId.get, AuthenticateUser, Variation.Success, "user presented no auth token")
+        prepareMaterialization(handler, query, None, clientAddress)
+    }
+  }
+
+  /**
+    * Circuits are only tracked once they fail and are forgotten once they recover,
+    * so that clients can't grow [[breakers]] by querying ever different targets.
+    */
+  def allowedByBreaker(circuit: String): Boolean =
+    !settings.circuitBreaker.enabled || breakers.get(circuit).forall(_.allow())
+
+  def breakerOf(circuit: String): SourceCircuitBreaker = breakers.getOrElse(circuit, {
+    breakers.retain((_, b) ⇒ !b.idle)
+    val breaker = new SourceCircuitBreaker(settings.circuitBreaker)
+    breakers.update(circuit, breaker)
+    breaker
+  })
+
+  def completeShutdownMaybe(): Unit = {
+    if (shuttingDown && (active.isEmpty || graceExpired)) {
+      shutdownWaiters.foreach(_ ! ShutdownComplete)
+      shutdownWaiters.clear()
     }
   }
 
@@ -68,6 +166,12 @@ class SonicdController(authService: ActorRef, authenticationTimeout: Timeout) ex
   //TODO deprecate queryId
   var handled: Long = 0L
 
+  val active = mutable.Map.empty[ActorRef, ActiveQuery]
+  val breakers = mutable.Map.empty[String, SourceCircuitBreaker]
+  var shuttingDown: Boolean = false
+  var graceExpired: Boolean = false
+  val shutdownWaiters = mutable.ListBuffer.empty[ActorRef]
+
   case class TokenValidationResult(user: Try[ApiUser], query: Query,
                                    handler: ActorRef, clientAddress: Option[InetAddress])
 
@@ -75,9 +179,9 @@ class SonicdController(authService: ActorRef, authenticationTimeout: Timeout) ex
 
   override def receive: Receive = {
 
-    case TokenValidationResult(f@Failure(e), query, handler, _) ⇒
+    case TokenValidationResult(Failure(e), query, handler, _) ⇒
       log.tylog(Level.INFO, query.traceId.get, AuthenticateUser, Variation.Failure(e), "token validation failed")
-      handler ! f
+      handler ! failed(e)
 
     case TokenValidationResult(Success(user), query, handler, clientAddress) ⇒
       try {
@@ -89,47 +193,87 @@ class SonicdController(authService: ActorRef, authenticationTimeout: Timeout) ex
       } catch {
         case e: Exception ⇒
           log.error(e, "error when preparing stream materialization")
-          handler ! Failure(e)
+          handler ! failed(e)
       }
 
     case NewCommand(a: Authenticate, _) ⇒ authService forward a
 
     case NewCommand(query: Query, clientAddress) ⇒
-      log.debug("client from {} posted new query {}", clientAddress, query)
-      val handler = sender()
-
-      log.tylog(Level.INFO, query.traceId.get, AuthenticateUser,
-        Variation.Attempt, "authenticating with auth: {}", query.auth)
-
-      query.auth match {
-        case Some(SonicdAuth(token)) ⇒
-          authService.ask(
-            ValidateToken(token, query.traceId.get))(authenticationTimeout)
-            .mapTo[Try[ApiUser]]
-            .map(tu ⇒ TokenValidationResult(tu, query, handler, clientAddress))
-            .recover {
-              case e: Exception ⇒ TokenValidationResult(Failure(e), query, handler, clientAddress)
-            }.pipeTo(self)
-
-        // if no provider is passed, then use sonicds auth
-        case Some(auth) ⇒
-          val providerClass = auth.provider
-          val result = try {
-            val provider = providerClass.getConstructors()(0).newInstance().asInstanceOf[ExternalAuthProvider]
-            provider.validate(auth, context.system, query.traceId.get)
-              .map(tu ⇒ TokenValidationResult(Success(tu), query, handler, clientAddress))
-          } catch {
-            case e: Exception ⇒ Future.failed(e)
+      val bytes = if (settings.maxQueryBytes > 0) queryBytes(query) else 0L
+      if (settings.maxQueryBytes > 0 && bytes > settings.maxQueryBytes) {
+        log.warning("rejecting query '{}' from {} of {} bytes", query.traceId.get, clientAddress, bytes)
+        sender() ! failed(new QueryTooLargeException(bytes, settings.maxQueryBytes))
+      } else authenticate(sender(), query, clientAddress)
+
+    case QueryStarted(query) ⇒
+      val publisher = sender()
+      context.watch(publisher)
+      active.update(publisher, query.copy(started = System.currentTimeMillis()))
+      if (shuttingDown) publisher ! ShuttingDown
+
+    case QueryFinished(success) ⇒
+      active.get(sender()).foreach { q ⇒
+        active.update(sender(), q.copy(success = Some(success)))
+        if (settings.circuitBreaker.enabled) {
+          if (success) breakers.get(q.circuit).foreach { breaker ⇒
+            breaker.success()
+            if (breaker.idle) breakers.remove(q.circuit)
+          } else {
+            val breaker = breakerOf(q.circuit)
+            val previous = breaker.state
+            breaker.failure()
+            if (previous != breaker.state && breaker.state == SourceCircuitBreaker.Open) {
+              log.warning("opened circuit of source {}", q.circuit)
+            }
           }
+        }
+      }
 
-          result.recover {
-            case e: Exception ⇒ TokenValidationResult(Failure(e), query, handler, clientAddress)
-          }.pipeTo(self)
+    case ListSessions ⇒
+      val now = System.currentTimeMillis()
+      Future.sequence(active.toVector.map { case (publisher, q) ⇒
+        publisher.ask(GuardedPublisher.GetRows)(settings.authenticationTimeout).mapTo[Long]
+          .map(Option.apply).recover { case NonFatal(_) ⇒ None }
+          .map(rows ⇒ Session(q.handler.path.name, q.clientAddress, q.traceId, q.source, now - q.started, rows))
+      }).map(Sessions).pipeTo(sender())
+
+    case GetCircuitBreakers ⇒
+      sender() ! breakers.map { case (source, breaker) ⇒ source → breaker.state.name }.toMap
+
+    case Terminated(publisher) if active.contains(publisher) ⇒
+      active.remove(publisher).foreach { q ⇒
+        val status = q.success match {
+          case Some(true) ⇒ "success"
+          case Some(false) ⇒ "failure"
+          case None if shuttingDown ⇒ "shutdown"
+          case None ⇒ "canceled"
+        }
+        auditLog.foreach(_.log(q, System.currentTimeMillis(), status))
+      }
+      completeShutdownMaybe()
+
+    case Terminated(handler) ⇒
+      val orphans = active.collect { case (publisher, q) if q.handler == handler ⇒ publisher }
+      if (orphans.nonEmpty) log.info("client disconnected: canceling {} queries", orphans.size)
+      orphans.foreach(_ ! ClientDisconnected)
+
+    case Shutdown(grace) ⇒
+      shutdownWaiters += sender()
+      if (!shuttingDown) {
+        shuttingDown = true
+        log.info("shutting down: draining {} active queries", active.size)
+        active.keys.foreach(_ ! ShuttingDown)
+        context.system.scheduler.scheduleOnce(grace, self, ShutdownGraceExpired)
+      }
+      completeShutdownMaybe()
 
-        case None ⇒
-          log.tylog(Level.INFO, query.traceId.get, AuthenticateUser, Variation.Success, "user presented no auth token")
-          prepareMaterialization(handler, query, None, clientAddress)
+    case ShutdownGraceExpired ⇒
+      graceExpired = true
+      if (active.nonEmpty) {
+        log.warning("shutdown grace period expired with {} active queries: {}",
+          active.size, active.values.map(_.traceId).mkString(","))
       }
+      completeShutdownMaybe()
 
     case m ⇒ log.warning("oops! It looks like I received the wrong message: {}", m)
 
@@ -149,6 +293,73 @@ object SonicdController {
     }
   }
 
+  /**
+    * Checks the per-user source ACL. An empty ACL disables the check.
+    * Otherwise users may only query the aliases listed under their name,
+    * and only users granted the wildcard `*` may query sources with an inline config.
+    */
+  def isAllowedSource(acl: Map[String, Set[String]], user: Option[ApiUser], alias: Option[String]): Boolean = {
+    acl.isEmpty || user.flatMap(u ⇒ acl.get(u.user)).exists { allowed ⇒
+      allowed.contains(AclWildcard) || alias.exists(allowed.contains)
+    }
+  }
+
+  val AclWildcard = "*"
+
+  /**
+    * Inline config keys that don't identify the target of a source
+    */
+  val CircuitIgnoredKeys = Set("user", "username", "password", "timeout-ms")
+
+  /**
+    * Name of the circuit breaker guarding the target of `query`. Aliases share a circuit, while inline configs
+    * get one per source class and config, credentials excluded. That way a client pointing
+    * an inline config at a dead host doesn't open the circuit of every other inline query of that class.
+    */
+  def circuitOf(query: Query): String = query.sourceAlias.getOrElse {
+    val target = JsObject(query.sonicdConfig.fields -- CircuitIgnoredKeys)
+    s"${query.sonicdSourceClass}@${Integer.toHexString(target.hashCode)}"
+  }
+
+  def queryBytes(query: Query): Long = query.query.getBytes(StandardCharsets.UTF_8).length
+
+  /**
+    * @param acl           per-user source aliases, see [[isAllowedSource]]
+    * @param verboseErrors send the cause chain of errors to clients, see [[VerboseException]]
+    * @param maxOutputRows rows after which queries are truncated, see [[GuardedPublisher]]. 0 disables it
+    * @param timeouts      query timeouts per source class
+    * @param maxQueryBytes queries whose text is longer are rejected. 0 disables it
+    * @param requireAuth   reject queries without an auth token
+    */
+  case class Settings(authenticationTimeout: Timeout, acl: Map[String, Set[String]], verboseErrors: Boolean,
+                      maxOutputRows: Long, circuitBreaker: SourceCircuitBreaker.Settings,
+                      timeouts: Map[String, QueryTimeouts], maxQueryBytes: Long, requireAuth: Boolean)
+
+  /**
+    * Timeouts of the queries of a source class.
+    *
+    * @param default applied to queries that don't set 'timeout-ms' in their config
+    * @param max     queries setting a longer 'timeout-ms' are rejected
+    */
+  case class QueryTimeouts(default: Option[FiniteDuration], max: Option[FiniteDuration])
+
+  def effectiveTimeout(sourceClass: String, timeouts: Option[QueryTimeouts],
+                       requested: Option[FiniteDuration]): Option[FiniteDuration] = {
+    val max = timeouts.flatMap(_.max)
+    requested match {
+      case Some(r) if r <= Duration.Zero ⇒ throw new InvalidTimeoutException(r)
+      case Some(r) if max.exists(r > _) ⇒ throw new TimeoutOverMaxException(sourceClass, r, max.get)
+      case Some(r) ⇒ Some(r)
+      case None ⇒ timeouts.flatMap(_.default)
+    }
+  }
+
+  /**
+    * Admins are users granted the ACL wildcard.
+    */
+  def isAdmin(acl: Map[String, Set[String]], user: Option[ApiUser]): Boolean =
+    user.flatMap(u ⇒ acl.get(u.user)).exists(_.contains(AclWildcard))
+
   def getDataSource(query: Query, context: ActorContext,
                     user: Option[ApiUser], clientAddress: Option[InetAddress]): DataSource = {
     getSourceClass(query)
@@ -160,7 +371,8 @@ object SonicdController {
   def getSourceClass(query: Query): Try[Class[_]] = {
     val clazzLoader = this.getClass.getClassLoader
 
-    Try(clazzLoader.loadClass(query.sonicdSourceClass))
+    if (query.sonicdSourceClass == SessionsSource.ReservedClass) Success(classOf[SessionsSource])
+    else Try(clazzLoader.loadClass(query.sonicdSourceClass))
       .orElse(Try(clazzLoader.loadClass("build.unstable.sonic.server.source." + query.sonicdSourceClass)))
       .orElse(Try(clazzLoader.loadClass("build.unstable.sonicd.source." + query.sonicdSourceClass)))
   }
@@ -183,6 +395,11 @@ object SonicdController {
           "object or an alias (string) that will be extracted by sonicd server")
     }
 
+    private[unstable] lazy val sourceAlias: Option[String] = query.config match {
+      case JsString(alias) ⇒ Some(alias)
+      case _ ⇒ None
+    }
+
     private[unstable] lazy val sonicdSourceClass: String = sonicdConfig.fields.getOrElse("class",
       throw new Exception(s"missing key 'class' in config")).convertTo[String]
 
@@ -193,4 +410,96 @@ object SonicdController {
       s"to access this source from ${clientAddress.getOrElse("unknown address")}")
       .getOrElse(s"unauthenticated user cannot access this source from ${clientAddress.getOrElse("unknown address")}. Please login first"))
 
+  /**
+    * Messages of `e` and each of its causes, outermost first.
+    */
+  def errorChain(e: Throwable): Vector[String] = {
+    val chain = Vector.newBuilder[String]
+    var current = e
+    var depth = 0
+    // depth cap guards against cyclic cause chains
+    while (current != null && depth < 32) {
+      chain += current.toString
+      current = if (current.getCause eq current) null else current.getCause
+      depth += 1
+    }
+    chain.result()
+  }
+
+  /**
+    * Carries the full cause chain of `cause` in its message so that it reaches the client.
+    * Only used when `sonicd.verbose-errors` is enabled, as causes may leak server internals.
+    */
+  class VerboseException(cause: Throwable)
+    extends Exception(errorChain(cause).mkString(" caused by: "), cause)
+
+  class SourceNotAllowedException(source: String) extends Exception(s"not authorized for source $source")
+
+  class AuthenticationRequiredException extends Exception("authentication required")
+
+  class ServerShuttingDownException extends Exception("server shutting down")
+
+  class QueryTooLargeException(bytes: Long, max: Long)
+    extends Exception(s"query too large: $bytes bytes exceeds the maximum of $max")
+
+  class InvalidTimeoutException(requested: FiniteDuration)
+    extends Exception(s"invalid timeout-ms ${requested.toMillis}: it must be greater than 0")
+
+  class TimeoutOverMaxException(sourceClass: String, requested: FiniteDuration, max: FiniteDuration)
+    extends Exception(s"requested timeout of ${requested.toMillis}ms exceeds the " +
+      s"maximum of ${max.toMillis}ms for source $sourceClass")
+
+  class SourceUnavailableException(source: String) extends Exception(s"source $source unavailable (circuit open)")
+
+  /**
+    * A query whose source is running.
+    *
+    * @param circuit circuit breaker tracking the outcome of the query, see [[circuitOf]]
+    * @param handler actor handling the client connection
+    * @param success outcome of the source's stream once it's known
+    */
+  case class ActiveQuery(traceId: String, source: String, circuit: String, user: Option[String],
+                         clientAddress: Option[InetAddress], query: String, handler: ActorRef,
+                         started: Long = 0L, success: Option[Boolean] = None)
+
+  /**
+    * Sent by [[GuardedPublisher]] when it starts, to be drained on shutdown.
+    */
+  case class QueryStarted(query: ActiveQuery)
+
+  /**
+    * Sent by [[GuardedPublisher]] with the outcome of its source's stream.
+    */
+  case class QueryFinished(success: Boolean)
+
+  case object ListSessions
+
+  /**
+    * @param connection name of the actor handling the client connection
+    * @param rows       output rows emitted so far, if the query's publisher replied in time
+    */
+  case class Session(connection: String, clientAddress: Option[InetAddress], traceId: String,
+                     source: String, elapsed: Long, rows: Option[Long])
+
+  case class Sessions(sessions: Vector[Session])
+
+  /**
+    * Replies with the state of the circuit breaker of every source that failed recently.
+    */
+  case object GetCircuitBreakers
+
+  /**
+    * Stops accepting new queries and drains active ones, replying [[ShutdownComplete]]
+    * once all of them terminated or after `grace`, whichever comes first. Idempotent.
+    */
+  case class Shutdown(grace: FiniteDuration)
+
+  case object ShuttingDown
+
+  case object ClientDisconnected
+
+  case object ShutdownComplete
+
+  private case object ShutdownGraceExpired
+
 }
@@ -0,0 +1,100 @@
+package build.unstable.sonicd.system.actor
+
+import scala.concurrent.duration.FiniteDuration
+
+object SourceCircuitBreaker {
+
+  sealed abstract class State(val name: String)
+
+  case object Closed extends State("closed")
+
+  case object Open extends State("open")
+
+  case object HalfOpen extends State("half-open")
+
+  /**
+    * @param maxFailures consecutive failures within `window` that open the breaker. 0 disables it
+    * @param cooldown    time the breaker stays open before letting a trial query through
+    */
+  case class Settings(maxFailures: Int, window: FiniteDuration, cooldown: FiniteDuration) {
+    val enabled: Boolean = maxFailures > 0
+  }
+
+}
+
+/**
+  * Tracks the outcome of the queries run against a source.
+  * Not thread-safe: it's only meant to be used from within [[SonicdController]].
+  *
+  * Opens after `maxFailures` consecutive failures within `window`. While open, [[allow]]
+  * returns false until `cooldown` elapses, after which a single trial query is let through (half-open).
+  * A successful trial closes the breaker, a failed one opens it again.
+  */
+class SourceCircuitBreaker(settings: SourceCircuitBreaker.Settings,
+                           clock: () ⇒ Long = () ⇒ System.currentTimeMillis()) {
+
+  import SourceCircuitBreaker._
+
+  private var _state: State = Closed
+  private var failures: Int = 0
+  private var firstFailureAt: Long = 0L
+  // time the breaker opened or, when half-open, the trial query started
+  private var since: Long = 0L
+
+  private def cooledDown(now: Long): Boolean = now - since >= settings.cooldown.toMillis
+
+  private def open(now: Long): Unit = {
+    _state = Open
+    since = now
+    failures = 0
+  }
+
+  def state: State = _state match {
+    case Open if cooledDown(clock()) ⇒ HalfOpen
+    case s ⇒ s
+  }
+
+  /**
+    * Closed with no failures within the window, so it behaves like a new breaker
+    */
+  def idle: Boolean =
+    _state == Closed && (failures == 0 || clock() - firstFailureAt > settings.window.toMillis)
+
+  /**
+    * Whether a new query can run against this source. When it
+    * returns true on a half-open breaker, that query is the trial.
+    */
+  def allow(): Boolean = {
+    val now = clock()
+    _state match {
+      case Closed ⇒ true
+      // a trial whose outcome never got reported does not block the source forever
+      case Open | HalfOpen if cooledDown(now) ⇒
+        _state = HalfOpen
+        since = now
+        true
+      case _ ⇒ false
+    }
+  }
+
+  def success(): Unit = _state match {
+    case Open ⇒ //query started before the breaker opened
+    case _ ⇒
+      _state = Closed
+      failures = 0
+  }
+
+  def failure(): Unit = if (settings.enabled) {
+    val now = clock()
+    _state match {
+      case HalfOpen ⇒ open(now)
+      case Closed ⇒
+        if (failures == 0 || now - firstFailureAt > settings.window.toMillis) {
+          failures = 1
+          firstFailureAt = now
+        } else failures += 1
+        if (failures >= settings.maxFailures) open(now)
+      case Open ⇒ //query started before the breaker opened
+    }
+  }
+}
@@ -0,0 +1,61 @@
+package build.unstable.sonicd
+
+import java.io.File
+import java.net.InetAddress
+import java.nio.file.Files
+
+import akka.actor.ActorRef
+import build.unstable.sonicd.system.actor.SonicdController.ActiveQuery
+import org.scalatest.{Matchers, WordSpec}
+import spray.json._
+
+import scala.collection.JavaConversions._
+
+class AuditLogSpec extends WordSpec with Matchers {
+
+  def withAuditLog(maxFileSize: String)(f: (AuditLog, File) ⇒ Unit): Unit = {
+    val dir = Files.createTempDirectory("sonicd-audit").toFile
+    val path = new File(dir, "audit.log")
+    val log = new AuditLog(AuditLog.Settings(path.getAbsolutePath, "size", maxFileSize, 3, compress = false, 10))
+    try f(log, path) finally {
+      log.close()
+      dir.listFiles().foreach(_.delete())
+      dir.delete()
+    }
+  }
+
+  val query = ActiveQuery("trace-1", "my_source", "my_source", Some("bob"), Some(InetAddress.getLoopbackAddress),
+    "select * from a_very_long_table_name", ActorRef.noSender, started = 0L)
+
+  "AuditLog" should {
+    "write one json line per query with its text truncated" in withAuditLog("10MB") { (log, path) ⇒
+      log.log(query, 1000L, "success")
+      log.close()
+
+      val lines = Files.readAllLines(path.toPath).toList
+      lines.size shouldBe 1
+      val entry = lines.head.parseJson.asJsObject.fields
+
+      entry("trace_id") shouldBe JsString("trace-1")
+      entry("user") shouldBe JsString("bob")
+      entry("client_address") shouldBe JsString("127.0.0.1")
+      entry("source") shouldBe JsString("my_source")
+      entry("query") shouldBe JsString("select * f…")
+      entry("started") shouldBe JsString("1970-01-01T00:00:00Z")
+      entry("finished") shouldBe JsString("1970-01-01T00:00:01Z")
+      entry("status") shouldBe JsString("success")
+    }
+
+    "roll over to a new file past the size threshold and retain the old one" in withAuditLog("1KB") { (log, path) ⇒
+      // the size check is only done every few appends, so write well past the threshold
+      (1 to 2000).foreach(i ⇒ log.log(query.copy(traceId = i.toString), 1000L, "success"))
+      log.close()
+
+      val rolled = new File(path.getAbsolutePath + ".1")
+      path.exists() shouldBe true
+      rolled.exists() shouldBe true
+      rolled.length() should be > 0L
+      path.getParentFile.listFiles().map(_.getName).toSet should not contain "audit.log.4"
+    }
+  }
+}
@@ -0,0 +1,50 @@
+package build.unstable.sonicd
+
+import build.unstable.sonicd.model.Fixture.loadConfig
+import build.unstable.sonicd.system.actor.SonicdController
+import org.scalatest.{Matchers, WordSpec}
+
+import scala.concurrent.duration._
+
+class SonicdConfigSpec extends WordSpec with Matchers {
+
+  "SonicdConfig" should {
+    "bind http and tcp listeners on the shared interface by default" in {
+      val config = loadConfig("sonicd.interface = \"10.0.0.1\"")
+
+      config.HTTP_INTERFACE shouldBe "10.0.0.1"
+      config.TCP_INTERFACE shouldBe "10.0.0.1"
+    }
+
+    "bind http and tcp listeners on independently configured interfaces" in {
+      val config = loadConfig("sonicd.http-interface = \"127.0.0.1\"\nsonicd.tcp-interface = \"0.0.0.0\"")
+
+      config.HTTP_INTERFACE shouldBe "127.0.0.1"
+      config.TCP_INTERFACE shouldBe "0.0.0.0"
+    }
+
+    "load per source class query timeouts" in {
+      val config = loadConfig("sonicd.timeouts { JdbcSource { default = 5m, max = 30m }, PrestoSource { max = 1h } }")
+
+      config.QUERY_TIMEOUTS shouldBe Map(
+        "JdbcSource" → SonicdController.QueryTimeouts(Some(5.minutes), Some(30.minutes)),
+        "PrestoSource" → SonicdController.QueryTimeouts(None, Some(1.hour)))
+    }
+
+    "fail to load malformed query timeouts" in {
+      an[Exception] should be thrownBy loadConfig("sonicd.timeouts { JdbcSource = 5m }")
+      an[Exception] should be thrownBy loadConfig("sonicd.timeouts { JdbcSource { default = five } }")
+      an[Exception] should be thrownBy loadConfig("sonicd.timeouts { JdbcSource { default = 0s } }")
+      an[Exception] should be thrownBy loadConfig("sonicd.timeouts { JdbcSource { default = 1h, max = 5m } }")
+    }
+
+    "disable the acl only when it's not configured" in {
+      loadConfig("").ACL shouldBe Map.empty
+      loadConfig("sonicd.acl { bandit = [\"test\"] }").ACL shouldBe Map("bandit" → Set("test"))
+    }
+
+    "fail to load a malformed acl instead of disabling it" in {
+      an[Exception] should be thrownBy loadConfig("sonicd.acl { bandit = \"test\" }")
+    }
+  }
+}
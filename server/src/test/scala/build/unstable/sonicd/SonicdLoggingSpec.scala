@@ -0,0 +1,31 @@
+package build.unstable.sonicd
+
+import org.scalatest.{Matchers, WordSpec}
+
+class SonicdLoggingSpec extends WordSpec with Matchers {
+
+  "SonicdLogging.truncate" should {
+    "leave strings within the limit untouched" in {
+      SonicdLogging.truncate("select 1", 8) shouldBe "select 1"
+      SonicdLogging.truncate("", 0) shouldBe ""
+    }
+
+    "truncate and append an ellipsis when over the limit" in {
+      SonicdLogging.truncate("select * from users", 6) shouldBe "select…"
+    }
+
+    "never split multibyte characters at the truncation boundary" in {
+      SonicdLogging.truncate("añb", 2) shouldBe "añ…"
+      // U+1F600 is encoded as a surrogate pair in a java String
+      val emoji = "😀"
+      SonicdLogging.truncate(s"a${emoji}b", 2) shouldBe s"a$emoji…"
+      SonicdLogging.truncate(s"a${emoji}b", 1) shouldBe "a…"
+      SonicdLogging.truncate(s"$emoji$emoji", 1) shouldBe s"$emoji…"
+    }
+
+    "not throw on non-positive limits" in {
+      SonicdLogging.truncate("select", 0) shouldBe "…"
+      SonicdLogging.truncate("select", -1) shouldBe "…"
+    }
+  }
+}
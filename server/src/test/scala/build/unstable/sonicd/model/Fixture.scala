@@ -11,7 +11,9 @@ import akka.testkit.CallingThreadDispatcher
 import build.unstable.sonic.model._
 import build.unstable.sonic.scaladsl.Sonic
 import build.unstable.sonic.server.source.SyntheticPublisher
+import build.unstable.sonicd.FromResourcesConfig
 import build.unstable.sonicd.source.file.FileWatcherWorker
+import com.typesafe.config.ConfigFactory
 import spray.json._
 
 object Fixture {
@@ -27,6 +29,13 @@ object Fixture {
 
   val testCtx = RequestContext("1", Some(testUser))
 
+  /**
+    * Server config from reference.conf with `overrides` applied
+    */
+  def loadConfig(overrides: String): FromResourcesConfig =
+    new FromResourcesConfig(ConfigFactory.parseString(overrides)
+      .withFallback(ConfigFactory.defaultReference())) {}
+
   val syntheticPubProps = Props(classOf[SyntheticPublisher], None, Some(1), 10, "1", false, None, testCtx)
     .withDispatcher(CallingThreadDispatcher.Id)
 
@@ -0,0 +1,40 @@
+package build.unstable.sonicd.service
+
+import akka.actor.{ActorContext, Props}
+import akka.stream.actor.ActorPublisher
+import akka.stream.actor.ActorPublisherMessage.{Cancel, Request}
+import build.unstable.sonic.model._
+import build.unstable.sonicd.SonicdLogging
+import build.unstable.sonicd.source.SonicdSource
+import build.unstable.sonicd.system.actor.SonicdController._
+import spray.json._
+
+import scala.collection.mutable
+
+/**
+ * Example source that replies with its own config as a single row
+ */
+class EchoSource(query: Query, actorContext: ActorContext, context: RequestContext)
+  extends SonicdSource(query, actorContext, context) {
+
+  override def publisher: Props = Props(classOf[EchoPublisher], query.sonicdConfig, context)
+}
+
+class EchoPublisher(config: JsObject)(implicit ctx: RequestContext) extends ActorPublisher[SonicMessage] with SonicdLogging {
+
+  val buffer: mutable.Queue[SonicMessage] = mutable.Queue(
+    StreamStarted(ctx.traceId),
+    TypeMetadata(config.fields.toVector),
+    OutputChunk(JsArray(config.fields.values.toVector)),
+    StreamCompleted.success
+  )
+
+  override def receive: Receive = {
+    case Request(_) ⇒
+      while (isActive && totalDemand > 0 && buffer.nonEmpty) {
+        onNext(buffer.dequeue())
+      }
+      if (buffer.isEmpty) onCompleteThenStop()
+    case Cancel ⇒ onCompleteThenStop()
+  }
+}
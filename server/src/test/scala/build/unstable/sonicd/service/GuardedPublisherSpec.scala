@@ -0,0 +1,174 @@
+package build.unstable.sonicd.service
+
+import akka.actor.{ActorRef, ActorSystem, Props}
+import akka.stream.actor.{ActorPublisher, ActorPublisherMessage}
+import akka.testkit.{CallingThreadDispatcher, ImplicitSender, TestActorRef, TestKit, TestProbe}
+import build.unstable.sonic.JsonProtocol._
+import build.unstable.sonic.model._
+import build.unstable.sonicd.model.Fixture._
+import build.unstable.sonicd.model.{HandlerUtils, ImplicitSubscriber}
+import build.unstable.sonicd.system.actor.{GuardedPublisher, SonicdController}
+import org.scalatest.{BeforeAndAfterAll, Matchers, WordSpecLike}
+
+import scala.concurrent.duration._
+
+class GuardedPublisherSpec(_system: ActorSystem)
+  extends TestKit(_system) with WordSpecLike
+    with Matchers with BeforeAndAfterAll with ImplicitSender
+    with ImplicitSubscriber with HandlerUtils {
+
+  def this() = this(ActorSystem("GuardedPublisherSpec"))
+
+  implicit val ctx: RequestContext = testCtx
+
+  val query = SonicdController.ActiveQuery(testCtx.traceId, "mock", "mock", None, None, "10", testActor)
+
+  override protected def afterAll(): Unit = {
+    TestKit.shutdownActorSystem(system)
+  }
+
+  def newPublisher(maxOutputRows: Long, controller: TestProbe = TestProbe(),
+                   timeout: Option[FiniteDuration] = None,
+                   verboseErrors: Boolean = false): TestActorRef[GuardedPublisher] = {
+    val ref = TestActorRef[GuardedPublisher](
+      Props(classOf[GuardedPublisher], Props[ProxyPublisher], query, maxOutputRows, timeout,
+        verboseErrors, controller.ref, testCtx)
+        .withDispatcher(CallingThreadDispatcher.Id))
+    ActorPublisher(ref).subscribe(subs)
+    watch(ref)
+    ref
+  }
+
+  def upstreamOf(pub: TestActorRef[GuardedPublisher]): ActorRef =
+    awaitAssert(pub.underlyingActor.context.child(GuardedPublisher.upstreamName).get)
+
+  "GuardedPublisher" should {
+    "register with the controller when started" in {
+      val controller = TestProbe()
+      val pub = newPublisher(0, controller)
+      controller.expectMsg(SonicdController.QueryStarted(query))
+      controller.lastSender shouldBe pub
+    }
+
+    "report the outcome of the upstream to the controller" in {
+      val controller = TestProbe()
+      val pub = newPublisher(0, controller)
+      controller.expectMsgType[SonicdController.QueryStarted]
+      pub ! ActorPublisherMessage.Request(10)
+
+      upstreamOf(pub) ! StreamCompleted.error(testCtx.traceId, new Exception("boom"))
+      controller.expectMsg(SonicdController.QueryFinished(false))
+      expectMsgType[StreamCompleted].success shouldBe false
+      expectMsg("complete")
+      expectTerminated(pub)
+    }
+
+    "include the cause chain of upstream errors when verbose errors are enabled" in {
+      val pub = newPublisher(0, verboseErrors = true)
+      pub ! ActorPublisherMessage.Request(10)
+
+      val cause = new java.sql.SQLException("connection refused")
+      upstreamOf(pub) ! StreamCompleted.error(testCtx.traceId, new Exception("boom", cause))
+      val done = expectMsgType[StreamCompleted]
+      done.error.get.getMessage shouldBe "java.lang.Exception: boom caused by: java.sql.SQLException: connection refused"
+      expectMsg("complete")
+      expectTerminated(pub)
+    }
+
+    "stream upstream messages untouched when under the output rows limit" in {
+      val pub = newPublisher(2)
+      pub ! ActorPublisherMessage.Request(10)
+      val upstream = upstreamOf(pub)
+
+      upstream ! StreamStarted(testCtx.traceId)
+      expectStreamStarted()
+
+      upstream ! OutputChunk(Vector(1))
+      expectMsgType[OutputChunk] shouldBe OutputChunk(Vector(1))
+
+      upstream ! StreamCompleted.success
+      expectDone(pub)
+    }
+
+    "not truncate a stream that emits exactly as many rows as the output rows limit" in {
+      val pub = newPublisher(2)
+      pub ! ActorPublisherMessage.Request(10)
+      val upstream = upstreamOf(pub)
+
+      upstream ! OutputChunk(Vector(1))
+      upstream ! OutputChunk(Vector(2))
+      upstream ! StreamCompleted.success
+
+      expectMsgType[OutputChunk] shouldBe OutputChunk(Vector(1))
+      expectMsgType[OutputChunk] shouldBe OutputChunk(Vector(2))
+      expectDone(pub)
+    }
+
+    "truncate the stream and signal it when the output rows limit is exceeded" in {
+      val pub = newPublisher(2)
+      pub ! ActorPublisherMessage.Request(10)
+      val upstream = upstreamOf(pub)
+
+      upstream ! OutputChunk(Vector(1))
+      upstream ! OutputChunk(Vector(2))
+      upstream ! OutputChunk(Vector(3))
+
+      expectMsgType[OutputChunk] shouldBe OutputChunk(Vector(1))
+      expectMsgType[OutputChunk] shouldBe OutputChunk(Vector(2))
+      expectMsgType[QueryProgress] shouldBe GuardedPublisher.truncatedProgress(2)
+      expectDone(pub)
+      expectNoMsg(100.millis)
+    }
+
+    "not limit the output when max output rows is disabled" in {
+      val pub = newPublisher(0)
+      pub ! ActorPublisherMessage.Request(10)
+      val upstream = upstreamOf(pub)
+
+      (1 to 5).foreach(i ⇒ upstream ! OutputChunk(Vector(i)))
+      (1 to 5).foreach(i ⇒ expectMsgType[OutputChunk] shouldBe OutputChunk(Vector(i)))
+
+      upstream ! StreamCompleted.success
+      expectDone(pub)
+    }
+
+    "flush buffered messages and complete with a shutdown error when the server shuts down" in {
+      val pub = newPublisher(0)
+      pub ! ActorPublisherMessage.Request(1)
+      val upstream = upstreamOf(pub)
+
+      upstream ! StreamStarted(testCtx.traceId)
+      upstream ! OutputChunk(Vector(1))
+      expectStreamStarted()
+      expectNoMsg(100.millis)
+
+      pub ! SonicdController.ShuttingDown
+      pub ! ActorPublisherMessage.Request(10)
+      expectMsgType[OutputChunk] shouldBe OutputChunk(Vector(1))
+      val done = expectMsgType[StreamCompleted]
+      done.success shouldBe false
+      done.error.get.getMessage shouldBe "server shutting down"
+      expectMsg("complete")
+      expectTerminated(pub)
+    }
+
+    "fail the query when its timeout elapses" in {
+      val controller = TestProbe()
+      val pub = newPublisher(0, controller, timeout = Some(300.millis))
+      pub ! ActorPublisherMessage.Request(10)
+      val upstream = upstreamOf(pub)
+
+      upstream ! StreamStarted(testCtx.traceId)
+      expectStreamStarted()
+
+      val done = expectMsgType[StreamCompleted]
+      done.error.get.getMessage shouldBe "query timed out after 300ms"
+      expectMsg("complete")
+      expectTerminated(pub)
+      controller.fishForMessage() {
+        case SonicdController.QueryFinished(success) ⇒ !success
+        case _ ⇒ false
+      }
+    }
+  }
+}
@@ -0,0 +1,67 @@
+package build.unstable.sonicd.service
+
+import java.net.Socket
+
+import akka.actor.ActorSystem
+import akka.http.scaladsl.Http
+import akka.http.scaladsl.model.{HttpRequest, HttpResponse, StatusCodes}
+import akka.io.Tcp
+import akka.stream.ActorMaterializer
+import akka.stream.scaladsl.Flow
+import akka.testkit.{TestKit, TestProbe}
+import build.unstable.sonicd.model.Fixture.loadConfig
+import build.unstable.sonicd.system.Listeners
+import org.scalatest.{BeforeAndAfterAll, Matchers, WordSpecLike}
+
+import scala.concurrent.Await
+import scala.concurrent.duration._
+
+class ListenersSpec(_system: ActorSystem) extends TestKit(_system)
+  with WordSpecLike with Matchers with BeforeAndAfterAll {
+
+  def this() = this(ActorSystem("ListenersSpec"))
+
+  implicit val materializer: ActorMaterializer = ActorMaterializer()
+
+  override protected def afterAll(): Unit = {
+    Http().shutdownAllConnectionPools()
+    TestKit.shutdownActorSystem(system)
+  }
+
+  "Listeners" should {
+    "bind http and tcp on their configured interfaces and ports and accept connections on both" in {
+      // port 0 binds each listener on its own ephemeral port
+      val config = loadConfig("sonicd.interface = \"0.0.0.0\"\n" +
+        "sonicd.http-interface = \"127.0.0.1\"\nsonicd.http-port = 0\n" +
+        "sonicd.tcp-interface = \"127.0.0.1\"\nsonicd.tcp-port = 0")
+      val tcpService = TestProbe()
+
+      val http = Await.result(Listeners.bind(config, Flow[HttpRequest].map(_ ⇒ HttpResponse()), tcpService.ref), 3.seconds)
+      val tcp = tcpService.expectMsgType[Tcp.Bound]
+      val tcpListener = tcpService.lastSender
+
+      try {
+        http.localAddress.getAddress.getHostAddress shouldBe "127.0.0.1"
+        tcp.localAddress.getAddress.getHostAddress shouldBe "127.0.0.1"
+        http.localAddress.getPort should not be tcp.localAddress.getPort
+
+        val res = Await.result(Http().singleRequest(
+          HttpRequest(uri = s"http://127.0.0.1:${http.localAddress.getPort}/")), 3.seconds)
+        res.status shouldBe StatusCodes.OK
+        res.discardEntityBytes()
+
+        // the tcp listener is bound in pull mode, as the tcp service expects
+        tcpService.send(tcpListener, Tcp.ResumeAccepting(1))
+        val client = new Socket(tcp.localAddress.getAddress, tcp.localAddress.getPort)
+        try {
+          tcpService.expectMsgType[Tcp.Connected]
+          tcpService.reply(Tcp.Register(TestProbe().ref))
+        } finally client.close()
+      } finally {
+        Await.ready(http.unbind(), 3.seconds)
+        tcpService.send(tcpListener, Tcp.Unbind)
+        tcpService.expectMsg(Tcp.Unbound)
+      }
+    }
+  }
+}
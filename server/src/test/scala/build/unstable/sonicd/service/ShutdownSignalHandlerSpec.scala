@@ -0,0 +1,27 @@
+package build.unstable.sonicd.service
+
+import build.unstable.sonicd.system.ShutdownSignalHandler
+import org.scalatest.{Matchers, WordSpec}
+import sun.misc.Signal
+
+import scala.collection.mutable
+
+class ShutdownSignalHandlerSpec extends WordSpec with Matchers {
+
+  "ShutdownSignalHandler" should {
+    "exit gracefully on the first signal and halt on the second" in {
+      val exits = mutable.ListBuffer.empty[Int]
+      val halts = mutable.ListBuffer.empty[Int]
+      val handler = new ShutdownSignalHandler(exits += _, halts += _)
+      val int = new Signal("INT")
+
+      handler.handle(int)
+      exits shouldBe Seq(128 + int.getNumber)
+      halts shouldBe empty
+
+      handler.handle(new Signal("TERM"))
+      exits.size shouldBe 1
+      halts shouldBe Seq(128 + new Signal("TERM").getNumber)
+    }
+  }
+}
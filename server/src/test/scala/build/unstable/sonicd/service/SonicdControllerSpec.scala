@@ -2,14 +2,17 @@ package build.unstable.sonicd.service
 
 import java.net.InetAddress
 
-import akka.actor.{ActorSystem, Props}
-import akka.testkit.{CallingThreadDispatcher, ImplicitSender, TestActorRef, TestKit}
+import akka.actor.{ActorRef, ActorSystem, Props, Terminated}
+import akka.stream.actor.{ActorPublisher, ActorPublisherMessage}
+import akka.testkit.{CallingThreadDispatcher, ImplicitSender, TestActor, TestActorRef, TestKit, TestProbe}
 import akka.util.Timeout
 import build.unstable.sonic.model.AuthConfig.Mode
 import build.unstable.sonic.model._
+import build.unstable.sonicd.AuditLog
 import build.unstable.sonicd.auth.ApiKey
-import build.unstable.sonicd.model.Fixture
-import build.unstable.sonicd.system.actor.{AuthenticationActor, SonicdController}
+import build.unstable.sonicd.model.{Fixture, ImplicitSubscriber}
+import build.unstable.sonicd.source.SessionsSource
+import build.unstable.sonicd.system.actor.{AuthenticationActor, GuardedPublisher, SonicdController, SourceCircuitBreaker}
 import com.auth0.jwt.JWTSigner
 import org.scalatest.{BeforeAndAfterAll, Matchers, WordSpecLike}
 import spray.json._
@@ -19,7 +22,7 @@ import scala.concurrent.duration._
 import scala.util.Failure
 
 class SonicdControllerSpec(_system: ActorSystem) extends TestKit(_system)
-  with WordSpecLike with Matchers with BeforeAndAfterAll with ImplicitSender {
+  with WordSpecLike with Matchers with BeforeAndAfterAll with ImplicitSender with ImplicitSubscriber {
 
   def this() = this(ActorSystem("SonicControllerSpec"))
 
@@ -27,10 +30,24 @@ class SonicdControllerSpec(_system: ActorSystem) extends TestKit(_system)
     TestKit.shutdownActorSystem(system)
   }
 
-  def newActor: TestActorRef[SonicdController] =
-    TestActorRef[SonicdController](Props(classOf[SonicdController], self, 1.seconds: Timeout)
+  def newActor: TestActorRef[SonicdController] = newActor(Map.empty[String, Set[String]])
+
+  def newActor(acl: Map[String, Set[String]],
+               verboseErrors: Boolean = false,
+               maxOutputRows: Long = 0L,
+               breakerSettings: SourceCircuitBreaker.Settings = SourceCircuitBreaker.Settings(0, 1.minute, 1.minute),
+               auditLog: Option[AuditLog] = None,
+               timeouts: Map[String, SonicdController.QueryTimeouts] = Map.empty,
+               maxQueryBytes: Long = 0L,
+               requireAuth: Boolean = false): TestActorRef[SonicdController] =
+    TestActorRef[SonicdController](Props(classOf[SonicdController], self, auditLog,
+      SonicdController.Settings(1.seconds: Timeout, acl, verboseErrors, maxOutputRows, breakerSettings,
+        timeouts, maxQueryBytes, requireAuth))
       .withDispatcher(CallingThreadDispatcher.Id))
 
+  def activeQuery(traceId: String, source: String, circuit: Option[String] = None): SonicdController.ActiveQuery =
+    SonicdController.ActiveQuery(traceId, source, circuit.getOrElse(source), None, None, "10", self)
+
   val signer = new JWTSigner("secret")
 
   "SonicController" should {
@@ -196,6 +213,417 @@ class SonicdControllerSpec(_system: ActorSystem) extends TestKit(_system)
 
       c.underlyingActor.handled shouldBe 1
     }
+
+    "accept queries on sources that are allowed by the user's acl entry" in {
+      val c = newActor(Map("bandit" → Set("test_server_config")))
+      val claims = ApiKey("1", Mode.Read, 1, None, None).toJWTClaims("bandit")
+      val user = AuthenticationActor.fromJWTClaims(claims)
+      val auth = SonicdAuth(signer.sign(claims))
+      val syntheticQuery = Query("10", JsString("test_server_config"), Some(auth)).copy(trace_id = Some("1234"))
+
+      c ! NewCommand(syntheticQuery, None)
+      expectMsgType[ValidateToken]
+
+      lastSender ! user
+      expectMsgType[Props]
+    }
+
+    "reject queries on sources that are not in the user's acl entry" in {
+      val c = newActor(Map("bandit" → Set("other_source")))
+      val claims = ApiKey("1", Mode.Read, 1, None, None).toJWTClaims("bandit")
+      val user = AuthenticationActor.fromJWTClaims(claims)
+      val auth = SonicdAuth(signer.sign(claims))
+      val syntheticQuery = Query("10", JsString("test_server_config"), Some(auth)).copy(trace_id = Some("1234"))
+
+      c ! NewCommand(syntheticQuery, None)
+      expectMsgType[ValidateToken]
+
+      lastSender ! user
+      val done = expectMsgType[Failure[_]]
+
+      assert(done.exception.isInstanceOf[SonicdController.SourceNotAllowedException])
+      done.exception.getMessage shouldBe "not authorized for source test_server_config"
+    }
+
+    "accept queries on any source, including inline configs, for users with the acl wildcard" in {
+      val c = newActor(Map("admin" → Set("*")))
+      val claims = ApiKey("1", Mode.Read, 1, None, None).toJWTClaims("admin")
+      val user = AuthenticationActor.fromJWTClaims(claims)
+      val auth = SonicdAuth(signer.sign(claims))
+
+      c ! NewCommand(Query("10", JsString("test_server_config"), Some(auth)).copy(trace_id = Some("1234")), None)
+      expectMsgType[ValidateToken]
+      lastSender ! user
+      expectMsgType[Props]
+
+      val config = """{"class" : "SyntheticSource"}""".parseJson.asJsObject
+      c ! NewCommand(Query("10", config, Some(auth)).copy(trace_id = Some("1234")), None)
+      expectMsgType[ValidateToken]
+      lastSender ! user
+      expectMsgType[Props]
+    }
+
+    "report only the top-level error message when verbose errors are disabled" in {
+      val c = newActor
+      val syntheticQuery = Query("10", JsString("does_not_exist"), None).copy(trace_id = Some("1234"))
+
+      c ! NewCommand(syntheticQuery, None)
+      val done = expectMsgType[Failure[_]]
+
+      done.exception.getMessage shouldBe "could not load query config 'does_not_exist'"
+    }
+
+    "include the error's cause chain when verbose errors are enabled" in {
+      val c = newActor(Map.empty[String, Set[String]], verboseErrors = true)
+      val syntheticQuery = Query("10", JsString("does_not_exist"), None).copy(trace_id = Some("1234"))
+
+      c ! NewCommand(syntheticQuery, None)
+      val done = expectMsgType[Failure[_]]
+
+      assert(done.exception.isInstanceOf[SonicdController.VerboseException])
+      val chain = done.exception.getMessage.split(" caused by: ")
+      chain.length shouldBe 2
+      chain.head should include("could not load query config 'does_not_exist'")
+      chain(1) should include("does_not_exist")
+    }
+
+    "instantiate the source registered under the config's class and stream its output" in {
+      val c = newActor
+      val config = """{"class" : "build.unstable.sonicd.service.EchoSource", "echo" : "hello"}""".parseJson.asJsObject
+      val query = Query("10", config, None).copy(trace_id = Some("1234"))
+
+      c ! NewCommand(query, None)
+      val pub = TestActorRef[GuardedPublisher](expectMsgType[Props])
+      ActorPublisher(pub).subscribe(subs)
+      watch(pub)
+
+      pub ! ActorPublisherMessage.Request(4)
+      expectMsgType[StreamStarted]
+      expectMsgType[TypeMetadata].typesHint.map(_._1) shouldBe Vector("class", "echo")
+      expectMsgType[OutputChunk] shouldBe OutputChunk(JsArray(config.fields.values.toVector))
+      expectMsgType[StreamCompleted].success shouldBe true
+      expectMsg("complete")
+      expectTerminated(pub)
+    }
+
+    "write executed queries to the audit log when they terminate" in {
+      val path = java.io.File.createTempFile("sonicd-audit", ".log")
+      val auditLog = new AuditLog(AuditLog.Settings(path.getAbsolutePath, "size", "10MB", 1, compress = false, 100))
+      val c = newActor(Map.empty[String, Set[String]], auditLog = Some(auditLog))
+      val config = """{"class" : "build.unstable.sonicd.service.EchoSource"}""".parseJson.asJsObject
+      val query = Query("10", config, None).copy(trace_id = Some("audited"))
+
+      try {
+        c ! NewCommand(query, None)
+        val pub = TestActorRef[GuardedPublisher](expectMsgType[Props])
+        ActorPublisher(pub).subscribe(subs)
+        watch(pub)
+        pub ! ActorPublisherMessage.Request(4)
+        expectMsgType[StreamStarted]
+        expectMsgType[TypeMetadata]
+        expectMsgType[OutputChunk]
+        expectMsgType[StreamCompleted]
+        expectMsg("complete")
+        expectTerminated(pub)
+
+        awaitAssert {
+          val entry = scala.io.Source.fromFile(path).getLines().toList.last.parseJson.asJsObject.fields
+          entry("trace_id") shouldBe JsString("audited")
+          entry("source") shouldBe JsString("build.unstable.sonicd.service.EchoSource")
+          entry("status") shouldBe JsString("success")
+        }
+      } finally {
+        auditLog.close()
+        path.delete()
+      }
+    }
+
+    "guard source publishers" in {
+      val c = newActor(Map.empty[String, Set[String]], maxOutputRows = 10L)
+
+      c ! NewCommand(Fixture.syntheticQuery, None)
+      expectMsgType[Props].actorClass() shouldBe classOf[GuardedPublisher]
+    }
+
+    "drain active queries with a shutdown error before completing shutdown" in {
+      val c = newActor
+      val query = Query("10", JsObject("class" → JsString("build.unstable.sonicd.service.MockSource")), None)
+        .copy(trace_id = Some("1234"))
+
+      c ! NewCommand(query, None)
+      val pub = TestActorRef[GuardedPublisher](expectMsgType[Props])
+      ActorPublisher(pub).subscribe(subs)
+      watch(pub)
+      pub ! ActorPublisherMessage.Request(10)
+      c.underlyingActor.active.values.map(_.traceId).toList shouldBe List("1234")
+
+      c ! SonicdController.Shutdown(5.seconds)
+      val done = expectMsgType[StreamCompleted]
+      done.error.get.getMessage shouldBe "server shutting down"
+      expectMsg("complete")
+      expectMsgAllClassOf(classOf[Terminated], SonicdController.ShutdownComplete.getClass)
+      c.underlyingActor.active shouldBe empty
+
+      // idempotent
+      c ! SonicdController.Shutdown(5.seconds)
+      expectMsg(SonicdController.ShutdownComplete)
+
+      // new queries are rejected
+      c ! NewCommand(query, None)
+      expectMsgType[Failure[_]].exception.getMessage shouldBe "server shutting down"
+    }
+
+    "complete shutdown after the grace period if queries are not drained" in {
+      val c = newActor
+      c.tell(SonicdController.QueryStarted(activeQuery("1234", "mock")), TestProbe().ref)
+
+      c ! SonicdController.Shutdown(100.millis)
+      expectNoMsg(50.millis)
+      expectMsg(SonicdController.ShutdownComplete)
+    }
+
+    "cancel all the queries of a client when it disconnects" in {
+      val c = newActor
+      val handler = TestProbe()
+      val watcher = TestProbe()
+      val query = Query("10", JsObject("class" → JsString("build.unstable.sonicd.service.MockSource")), None)
+
+      val upstreams = (1 to 2).map { i ⇒
+        handler.send(c, NewCommand(query.copy(trace_id = Some(i.toString)), None))
+        val pub = TestActorRef[GuardedPublisher](handler.expectMsgType[Props])
+        pub ! ActorPublisherMessage.Request(1)
+        val upstream = awaitAssert(pub.underlyingActor.context.child(GuardedPublisher.upstreamName).get)
+        watcher.watch(upstream)
+        upstream
+      }
+      c.underlyingActor.active.size shouldBe 2
+
+      system.stop(handler.ref)
+      upstreams.foreach(watcher.expectTerminated(_))
+      awaitAssert(c.underlyingActor.active shouldBe empty)
+    }
+
+    "list active queries to admins with the sessions meta-query" in {
+      val c = newActor(Map("admin" → Set(SonicdController.AclWildcard)))
+      val running = TestProbe()
+      running.setAutoPilot(new TestActor.AutoPilot {
+        def run(sender: ActorRef, msg: Any): TestActor.AutoPilot = msg match {
+          case GuardedPublisher.GetRows ⇒ sender ! 42L; TestActor.KeepRunning
+          case _ ⇒ TestActor.KeepRunning
+        }
+      })
+      c.tell(SonicdController.QueryStarted(activeQuery("running", "mock")), running.ref)
+
+      val claims = ApiKey("1", Mode.Read, 1, None, None).toJWTClaims("admin")
+      val user = AuthenticationActor.fromJWTClaims(claims)
+      val auth = SonicdAuth(signer.sign(claims))
+      val sessionsQuery = Query("", JsObject("class" → JsString(SessionsSource.ReservedClass)), Some(auth))
+        .copy(trace_id = Some("sessions"))
+
+      c ! NewCommand(sessionsQuery, None)
+      expectMsgType[ValidateToken]
+      lastSender ! user
+      val pub = TestActorRef[GuardedPublisher](expectMsgType[Props])
+      ActorPublisher(pub).subscribe(subs)
+      watch(pub)
+      pub ! ActorPublisherMessage.Request(10)
+
+      expectMsgType[StreamStarted]
+      expectMsgType[TypeMetadata] shouldBe TypeMetadata(SessionsSource.columns)
+      // the sessions query itself is also running
+      val rows = Vector(expectMsgType[OutputChunk], expectMsgType[OutputChunk]).map(_.data.elements)
+      val row = rows.find(_(2) == JsString("running")).get
+      row(0) shouldBe JsString(self.path.name)
+      row(1) shouldBe JsNull
+      row(3) shouldBe JsString("mock")
+      row(5) shouldBe JsNumber(42)
+      rows.map(_(2)) should contain(JsString("sessions"))
+      expectMsgType[StreamCompleted].success shouldBe true
+      expectMsg("complete")
+      expectTerminated(pub)
+    }
+
+    "reject the sessions meta-query from non admins" in {
+      val c = newActor
+      val sessionsQuery = Query("", JsObject("class" → JsString(SessionsSource.ReservedClass)), None)
+        .copy(trace_id = Some("sessions"))
+
+      c ! NewCommand(sessionsQuery, None)
+      expectMsgType[Failure[_]].exception.getMessage shouldBe s"not authorized for source ${SessionsSource.ReservedClass}"
+    }
+
+    "apply the default timeout of the source class to queries that don't set one" in {
+      val timeouts = Map("EchoSource" → SonicdController.QueryTimeouts(Some(5.seconds), Some(1.minute)))
+      val c = newActor(Map.empty[String, Set[String]], timeouts = timeouts)
+      val config = JsObject("class" → JsString("build.unstable.sonicd.service.EchoSource"))
+
+      c ! NewCommand(Query("10", config, None).copy(trace_id = Some("1234")), None)
+      expectMsgType[Props].args should contain(Some(5.seconds))
+    }
+
+    "let clients shorten the timeout of their queries" in {
+      val timeouts = Map("EchoSource" → SonicdController.QueryTimeouts(Some(5.seconds), Some(1.minute)))
+      val c = newActor(Map.empty[String, Set[String]], timeouts = timeouts)
+      val config = JsObject("class" → JsString("build.unstable.sonicd.service.EchoSource"),
+        "timeout-ms" → JsNumber(1000))
+
+      c ! NewCommand(Query("10", config, None).copy(trace_id = Some("1234")), None)
+      expectMsgType[Props].args should contain(Some(1.second))
+    }
+
+    "reject queries requesting a timeout over the source class max" in {
+      val timeouts = Map("EchoSource" → SonicdController.QueryTimeouts(Some(5.seconds), Some(1.minute)))
+      val c = newActor(Map.empty[String, Set[String]], timeouts = timeouts)
+      val config = JsObject("class" → JsString("build.unstable.sonicd.service.EchoSource"),
+        "timeout-ms" → JsNumber(2.minutes.toMillis))
+
+      c ! NewCommand(Query("10", config, None).copy(trace_id = Some("1234")), None)
+      expectMsgType[Failure[_]].exception.getMessage shouldBe
+        "requested timeout of 120000ms exceeds the maximum of 60000ms for source EchoSource"
+    }
+
+    "reject queries requesting a timeout that is not positive" in {
+      val c = newActor(Map.empty[String, Set[String]])
+
+      Seq(0, -1).foreach { ms ⇒
+        val config = JsObject("class" → JsString("build.unstable.sonicd.service.EchoSource"),
+          "timeout-ms" → JsNumber(ms))
+        c ! NewCommand(Query("10", config, None).copy(trace_id = Some("1234")), None)
+        expectMsgType[Failure[_]].exception.getMessage shouldBe s"invalid timeout-ms $ms: it must be greater than 0"
+      }
+    }
+
+    "reject queries over the max query bytes before authenticating them" in {
+      val c = newActor(Map.empty[String, Set[String]], maxQueryBytes = 10L)
+      val auth = SonicdAuth(signer.sign(ApiKey("1", Mode.Read, 1, None, None).toJWTClaims("bandit")))
+      val config = JsObject("class" → JsString("build.unstable.sonicd.service.EchoSource"))
+
+      // multibyte characters count as their utf-8 encoded length
+      c ! NewCommand(Query("ñññññ1", config, Some(auth)).copy(trace_id = Some("1234")), None)
+      expectMsgType[Failure[_]].exception.getMessage shouldBe "query too large: 11 bytes exceeds the maximum of 10"
+      expectNoMsg(100.millis)
+
+      c ! NewCommand(Query("ñññññ", config, None).copy(trace_id = Some("1234")), None)
+      expectMsgType[Props]
+    }
+
+    "reject queries without auth when auth is required" in {
+      val c = newActor(Map.empty[String, Set[String]], requireAuth = true)
+      val config = JsObject("class" → JsString("SyntheticSource"))
+
+      c ! NewCommand(Query("10", config, None).copy(trace_id = Some("1234")), None)
+      expectMsgType[Failure[_]].exception.getMessage shouldBe "authentication required"
+      c.underlyingActor.handled shouldBe 0
+
+      val claims = ApiKey("1", Mode.Read, 1, None, None).toJWTClaims("bandit")
+      val auth = SonicdAuth(signer.sign(claims))
+      c ! NewCommand(Query("10", config, Some(auth)).copy(trace_id = Some("1234")), None)
+      expectMsgType[ValidateToken]
+      lastSender ! AuthenticationActor.fromJWTClaims(claims)
+      expectMsgType[Props]
+    }
+
+    "accept queries without auth when auth is not required" in {
+      val c = newActor(Map.empty[String, Set[String]], requireAuth = false)
+      val config = JsObject("class" → JsString("SyntheticSource"))
+
+      c ! NewCommand(Query("10", config, None).copy(trace_id = Some("1234")), None)
+      expectMsgType[Props]
+      c.underlyingActor.handled shouldBe 1
+    }
+
+    "fail fast queries on sources whose circuit is open" in {
+      val c = newActor(Map.empty[String, Set[String]],
+        breakerSettings = SourceCircuitBreaker.Settings(2, 1.minute, 1.minute))
+      val source = "build.unstable.sonicd.service.MockSource"
+      val query = Query("10", JsObject("class" → JsString(source)), None).copy(trace_id = Some("1234"))
+      val circuit = SonicdController.circuitOf(query)
+
+      (1 to 2).foreach { i ⇒
+        val pub = TestProbe()
+        c.tell(SonicdController.QueryStarted(activeQuery(i.toString, source, Some(circuit))), pub.ref)
+        c.tell(SonicdController.QueryFinished(success = false), pub.ref)
+      }
+
+      c ! SonicdController.GetCircuitBreakers
+      expectMsg(Map(circuit → "open"))
+
+      c ! NewCommand(query, None)
+      expectMsgType[Failure[_]].exception.getMessage shouldBe s"source $circuit unavailable (circuit open)"
+    }
+
+    "not track circuits when the circuit breaker is disabled" in {
+      val c = newActor
+      val pub = TestProbe()
+      c.tell(SonicdController.QueryStarted(activeQuery("1", "mock")), pub.ref)
+      c.tell(SonicdController.QueryFinished(success = false), pub.ref)
+
+      c ! SonicdController.GetCircuitBreakers
+      expectMsg(Map.empty[String, String])
+    }
+
+    "only track circuits that failed and forget them once they recover" in {
+      val c = newActor(Map.empty[String, Set[String]],
+        breakerSettings = SourceCircuitBreaker.Settings(3, 1.minute, 1.minute))
+
+      def finish(circuit: String, success: Boolean): Unit = {
+        val pub = TestProbe()
+        c.tell(SonicdController.QueryStarted(activeQuery("1", circuit)), pub.ref)
+        c.tell(SonicdController.QueryFinished(success), pub.ref)
+      }
+
+      finish("a", success = true)
+      c ! SonicdController.GetCircuitBreakers
+      expectMsg(Map.empty[String, String])
+
+      finish("b", success = false)
+      c ! SonicdController.GetCircuitBreakers
+      expectMsg(Map("b" → "closed"))
+
+      finish("b", success = true)
+      c ! SonicdController.GetCircuitBreakers
+      expectMsg(Map.empty[String, String])
+    }
+
+    "not use up the trial of a half-open circuit on queries rejected before running" in {
+      val c = newActor(Map.empty[String, Set[String]],
+        breakerSettings = SourceCircuitBreaker.Settings(1, 1.minute, 200.millis))
+      val source = "build.unstable.sonicd.service.EchoSource"
+      def query(timeoutMs: Long): Query = Query("10", JsObject("class" → JsString(source),
+        "timeout-ms" → JsNumber(timeoutMs)), None).copy(trace_id = Some("1234"))
+
+      val pub = TestProbe()
+      c.tell(SonicdController.QueryStarted(activeQuery("1", source, Some(SonicdController.circuitOf(query(1000))))), pub.ref)
+      c.tell(SonicdController.QueryFinished(success = false), pub.ref)
+      Thread.sleep(250)
+
+      c ! NewCommand(query(0), None)
+      expectMsgType[Failure[_]].exception.getMessage shouldBe "invalid timeout-ms 0: it must be greater than 0"
+
+      c ! NewCommand(query(1000), None)
+      expectMsgType[Props]
+    }
+
+    "keep a circuit per target of inline configs of the same source class" in {
+      val c = newActor(Map.empty[String, Set[String]],
+        breakerSettings = SourceCircuitBreaker.Settings(1, 1.minute, 1.minute))
+      val source = "build.unstable.sonicd.service.MockSource"
+      def query(url: String, password: String): Query = Query("10", JsObject(
+        "class" → JsString(source), "url" → JsString(url), "password" → JsString(password)), None)
+        .copy(trace_id = Some("1234"))
+
+      val dead = query("jdbc:dead", "a")
+      val pub = TestProbe()
+      c.tell(SonicdController.QueryStarted(activeQuery("1", source, Some(SonicdController.circuitOf(dead)))), pub.ref)
+      c.tell(SonicdController.QueryFinished(success = false), pub.ref)
+
+      // credentials don't identify the target
+      c ! NewCommand(query("jdbc:dead", "b"), None)
+      expectMsgType[Failure[_]].exception.getMessage should endWith("unavailable (circuit open)")
+
+      c ! NewCommand(query("jdbc:alive", "a"), None)
+      expectMsgType[Props]
+    }
   }
 }
 
@@ -210,4 +638,3 @@ class AlwaysFailsProvider extends ExternalAuthProvider {
   override def validate(auth: AuthConfig, system: ActorSystem, traceId: String): Future[ApiUser] =
     Future.failed(new Exception("not a chance!"))
 }
-
@@ -0,0 +1,87 @@
+package build.unstable.sonicd.service
+
+import build.unstable.sonicd.system.actor.SourceCircuitBreaker
+import build.unstable.sonicd.system.actor.SourceCircuitBreaker._
+import org.scalatest.{Matchers, WordSpec}
+
+import scala.concurrent.duration._
+
+class SourceCircuitBreakerSpec extends WordSpec with Matchers {
+
+  trait Clock {
+    var now: Long = 0L
+    val breaker = new SourceCircuitBreaker(Settings(3, 1.minute, 30.seconds), () ⇒ now)
+
+    def failTimes(n: Int): Unit = (1 to n).foreach { _ ⇒
+      breaker.allow() shouldBe true
+      breaker.failure()
+    }
+  }
+
+  "SourceCircuitBreaker" should {
+    "open after max consecutive failures within the window" in new Clock {
+      failTimes(2)
+      breaker.state shouldBe Closed
+      failTimes(1)
+      breaker.state shouldBe Open
+      breaker.allow() shouldBe false
+    }
+
+    "reset the consecutive failures count on success" in new Clock {
+      failTimes(2)
+      breaker.success()
+      failTimes(2)
+      breaker.state shouldBe Closed
+    }
+
+    "not open if the failures are spread beyond the window" in new Clock {
+      failTimes(2)
+      now += 2.minutes.toMillis
+      failTimes(2)
+      breaker.state shouldBe Closed
+    }
+
+    "let a single trial query through after the cooldown and close if it succeeds" in new Clock {
+      failTimes(3)
+      now += 30.seconds.toMillis
+      breaker.state shouldBe HalfOpen
+      breaker.allow() shouldBe true
+      breaker.allow() shouldBe false
+      breaker.success()
+      breaker.state shouldBe Closed
+      breaker.allow() shouldBe true
+    }
+
+    "open again if the trial query fails" in new Clock {
+      failTimes(3)
+      now += 30.seconds.toMillis
+      breaker.allow() shouldBe true
+      breaker.failure()
+      breaker.state shouldBe Open
+      breaker.allow() shouldBe false
+    }
+
+    "ignore successes of queries started before it opened" in new Clock {
+      failTimes(3)
+      breaker.success()
+      breaker.state shouldBe Open
+      breaker.allow() shouldBe false
+    }
+
+    "be idle when closed without failures within the window" in new Clock {
+      breaker.idle shouldBe true
+      failTimes(1)
+      breaker.idle shouldBe false
+      now += 2.minutes.toMillis
+      breaker.idle shouldBe true
+      failTimes(3)
+      breaker.idle shouldBe false
+    }
+
+    "never open when disabled" in {
+      val breaker = new SourceCircuitBreaker(Settings(0, 1.minute, 30.seconds))
+      (1 to 10).foreach(_ ⇒ breaker.failure())
+      breaker.allow() shouldBe true
+    }
+  }
+}
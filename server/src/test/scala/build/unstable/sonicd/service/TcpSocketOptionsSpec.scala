@@ -0,0 +1,61 @@
+package build.unstable.sonicd.service
+
+import java.net.{InetSocketAddress, Socket}
+
+import akka.actor.ActorSystem
+import akka.io.{IO, Inet, Tcp}
+import akka.testkit.{ImplicitSender, TestKit, TestProbe}
+import build.unstable.sonicd.model.Fixture.loadConfig
+import org.scalatest.{BeforeAndAfterAll, Matchers, WordSpecLike}
+
+import scala.concurrent.duration._
+import scala.concurrent.{Await, Promise}
+
+class TcpSocketOptionsSpec(_system: ActorSystem) extends TestKit(_system)
+  with WordSpecLike with Matchers with BeforeAndAfterAll with ImplicitSender {
+
+  def this() = this(ActorSystem("TcpSocketOptionsSpec"))
+
+  override protected def afterAll(): Unit = {
+    TestKit.shutdownActorSystem(system)
+  }
+
+  /**
+    * Binds with `options` and returns the TCP_NODELAY of the first accepted connection
+    */
+  def acceptedNoDelay(options: List[Inet.SocketOption]): Boolean = {
+    val noDelay = Promise[Boolean]()
+    // options are applied in order, so this one sees the socket as configured by the previous ones
+    val probe = new Inet.SocketOption {
+      override def afterConnect(s: Socket): Unit = noDelay.trySuccess(s.getTcpNoDelay)
+    }
+
+    IO(Tcp) ! Tcp.Bind(self, new InetSocketAddress("127.0.0.1", 0), options = options :+ probe)
+    val bound = expectMsgType[Tcp.Bound]
+    val listener = lastSender
+    val client = new Socket(bound.localAddress.getAddress, bound.localAddress.getPort)
+    try {
+      expectMsgType[Tcp.Connected]
+      lastSender ! Tcp.Register(TestProbe().ref)
+      Await.result(noDelay.future, 3.seconds)
+    } finally {
+      client.close()
+      listener ! Tcp.Unbind
+      expectMsg(Tcp.Unbound)
+    }
+  }
+
+  "SonicdConfig tcp socket options" should {
+    "disable Nagle's algorithm on accepted connections by default" in {
+      val config = loadConfig("")
+      config.TCP_NO_DELAY shouldBe true
+      acceptedNoDelay(config.TCP_SOCKET_OPTIONS) shouldBe true
+    }
+
+    "leave Nagle's algorithm enabled on accepted connections when tcp-no-delay is off" in {
+      val config = loadConfig("sonicd.tcp-no-delay = false")
+      config.TCP_NO_DELAY shouldBe false
+      acceptedNoDelay(config.TCP_SOCKET_OPTIONS) shouldBe false
+    }
+  }
+}
@@ -254,6 +254,25 @@ class JdbcSourceSpec(_system: ActorSystem)
       testConnectionOpen()
     }
 
+    "encode decimals without losing precision" in {
+      runQuery("CREATE TABLE decimals_test(amount DECIMAL(38, 10))")()
+      runQuery("INSERT INTO decimals_test VALUES (123456789012345678.1234567891)")()
+      runQuery("INSERT INTO decimals_test VALUES (NULL)")()
+
+      val pub = newPublisher("select * from decimals_test")
+      pub ! ActorPublisherMessage.Request(1)
+      expectMsg(TypeMetadata(Vector(("AMOUNT", JsNumber(0.0)))))
+      pub ! ActorPublisherMessage.Request(1)
+      val chunk = expectMsgType[OutputChunk]
+      chunk shouldBe OutputChunk(JsArray(Vector(JsNumber(BigDecimal("123456789012345678.1234567891")))))
+      chunk.data.compactPrint shouldBe "[123456789012345678.1234567891]"
+      pub ! ActorPublisherMessage.Request(1)
+      expectMsg(OutputChunk(JsArray(Vector(JsNull))))
+      pub ! ActorPublisherMessage.Request(1)
+      expectDone(pub)
+      testConnectionOpen()
+    }
+
     "should send type metadata" in {
       runQuery("CREATE TABLE test4(id VARCHAR, a BIGINT)")()
       runQuery("INSERT INTO test4 (id, a) VALUES ('1234', 1234)")()